@@ -1,8 +1,23 @@
 // Based (read: ripped) off of resolv by @solarlune
 
+mod broadphase;
+mod ray;
+mod sweep;
+mod layers;
+mod query;
+mod response;
+mod push;
+
 use std::convert::AsRef;
+use std::cmp::Ordering;
+use std::ops::{Deref, DerefMut};
 use crate::generation::{GenerationalIndex, GenerationalIndexAllocator, GenerationalIndexArray};
 use cgmath::{prelude::*, Vector2, Point2, dot};
+use self::broadphase::SpatialHash;
+pub use self::ray::RayHit;
+pub use self::sweep::SweepHit;
+pub use self::layers::CollisionLayers;
+pub use self::response::ResponseType;
 
 #[derive(Clone, Debug)]
 pub struct Shape {
@@ -10,9 +25,39 @@ pub struct Shape {
     y: f32,
     tags: Vec<String>,
     collidable: bool,
+    // Sensors still report overlaps, but nothing should ever be pushed out of them.
+    sensor: bool,
+    // Which layers the shape is on, and which layers it's allowed to touch. Two shapes only
+    // collide if each one's mask covers the other's layer. Compound children don't have a say.
+    layer: u32,
+    mask: u32,
+    body: BodyKind,
+    // One-way shapes (ledges) let things through going this way, and only block them coming back.
+    one_way: Option<Vector2<f32>>,
     stype: ShapeType
 }
 
+pub const DEFAULT_LAYER: u32 = 1;
+pub const ALL_LAYERS: u32 = std::u32::MAX;
+
+// How close counts as touching. Moves should stop this far off a surface, and a shape this far
+// into another one is still only touching it, not overlapping.
+pub const CONTACT_SKIN: f32 = 0.01;
+// How close to straight against a one-way shape's direction a face has to be to let things in through it.
+const ONE_WAY_MIN_ALIGNMENT: f32 = 0.99;
+
+// How a shape takes part in the simulation. Static shapes (walls, mostly) never move, so they live in
+// their own grid that only changes when one is added or removed, and two of them are never tested
+// against each other. Kinematic shapes are moved by game code and block, but are never resolved.
+// Only dynamic shapes get pushed out of things.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BodyKind {
+    Static,
+    Kinematic,
+    #[default]
+    Dynamic,
+}
+
 #[derive(Clone, Debug)]
 pub struct Collision {
     // Displacement of shape to point of collision
@@ -20,27 +65,27 @@ pub struct Collision {
     pub resolve_y: f32,
     pub shape_a: ShapeIndex,
     pub shape_b: ShapeIndex,
+    // Either shape is a sensor, so this is just an overlap and shouldn't be resolved.
+    pub sensor: bool,
+    // Either shape is one-way. Being inside one means we're partway across, so don't resolve this either.
+    pub one_way: bool,
 }
 
 #[derive(Clone, Debug)]
 enum ShapeType {
     AABB(AABB),
+    Circle(Circle),
+    ConvexPolygon(ConvexPolygon),
     Compound(Compound),
 }
 
-impl ShapeType {
-    pub fn shifted(&self, dx: f32, dy: f32) -> ShapeType {
-        match self {
-            ShapeType::AABB(ref aabb) => {
-                ShapeType::AABB(*aabb)
-            },
-            ShapeType::Compound(ref compound) => {
-                ShapeType::Compound(Compound(compound.0.iter().map(|x| x.shifted(dx, dy)).collect()))
-            },
-        }
-    }
+// Points are relative to the shape's position, and always wound so the signed area is positive.
+#[derive(Clone, Debug)]
+struct ConvexPolygon {
+    points: Vec<Point2<f32>>
 }
 
+// Child positions are relative to the parent shape's position.
 #[derive(Clone, Debug)]
 struct Compound(Vec<Shape>);
 
@@ -50,6 +95,12 @@ struct AABB {
     h: f32
 }
 
+// The shape's position is the center of the circle, not its top-left.
+#[derive(Clone, Debug, Copy)]
+struct Circle {
+    r: f32
+}
+
 pub fn generate_edges(verticies: Vec<Point2<f32>>) -> Vec<Vector2<f32>> {
     let mut edges = vec![];
 
@@ -120,8 +171,49 @@ impl AABB {
         ]
     }
 
-    fn generate_edges(&self, s: &Shape) -> Vec<Vector2<f32>> {
-        generate_edges(self.generate_points(s))
+}
+
+impl ConvexPolygon {
+    fn new(mut points: Vec<Point2<f32>>) -> Result<ConvexPolygon, String> {
+        if points.len() < 3 {
+            return Err(format!("A polygon needs at least 3 points, got {}", points.len()));
+        }
+
+        let area = points.iter().zip(points.iter().cycle().skip(1))
+            .map(|(p, q)| p.x * q.y - q.x * p.y)
+            .sum::<f32>();
+        if area == 0.0 {
+            return Err("Polygon has no area".to_string());
+        } else if area < 0.0 {
+            points.reverse();
+        }
+
+        // Every turn has to go the same way, and they have to add up to exactly one trip around,
+        // otherwise we've got something like a star that only looks convex corner by corner.
+        let edges = generate_edges(points.clone());
+        // A point repeated back to back leaves an edge with no direction, which SAT can't test against.
+        if edges.iter().any(|x| x.magnitude2() == 0.0) {
+            return Err("Polygon has a repeated point".to_string());
+        }
+        let mut turning = 0.0;
+        for (e1, e2) in edges.iter().zip(edges.iter().cycle().skip(1)) {
+            let cross = e1.x * e2.y - e1.y * e2.x;
+            if cross < 0.0 {
+                return Err("Polygon is not convex".to_string());
+            }
+            turning += cross.atan2(dot(*e1, *e2));
+        }
+        if turning > 2.0 * std::f32::consts::PI + 1e-3 {
+            return Err("Polygon is not convex".to_string());
+        }
+
+        Ok(ConvexPolygon {
+            points
+        })
+    }
+
+    fn generate_points(&self, s: &Shape) -> Vec<Point2<f32>> {
+        self.points.iter().map(|p| Point2::new(s.x + p.x, s.y + p.y)).collect()
     }
 }
 
@@ -131,6 +223,11 @@ impl Shape {
             x, y,
             tags: vec![],
             collidable: true,
+            sensor: false,
+            layer: DEFAULT_LAYER,
+            mask: ALL_LAYERS,
+            body: BodyKind::Dynamic,
+            one_way: None,
             stype: ShapeType::AABB(AABB {
                 w, h,
             })
@@ -142,6 +239,11 @@ impl Shape {
             x: x1, y: y1,
             tags: vec![],
             collidable: true,
+            sensor: false,
+            layer: DEFAULT_LAYER,
+            mask: ALL_LAYERS,
+            body: BodyKind::Dynamic,
+            one_way: None,
             stype: ShapeType::AABB(AABB {
                 w: x2 - x1,
                 h: y2 - y1,
@@ -149,26 +251,70 @@ impl Shape {
         }
     }
 
+    pub fn new_circle(x: f32, y: f32, r: f32) -> Shape {
+        Shape {
+            x, y,
+            tags: vec![],
+            collidable: true,
+            sensor: false,
+            layer: DEFAULT_LAYER,
+            mask: ALL_LAYERS,
+            body: BodyKind::Dynamic,
+            one_way: None,
+            stype: ShapeType::Circle(Circle {
+                r,
+            })
+        }
+    }
+
+    // Points are relative to (x, y), in either winding order. Fails if they don't form a convex polygon.
+    pub fn new_convex_polygon(x: f32, y: f32, points: Vec<Point2<f32>>) -> Result<Shape, String> {
+        Ok(Shape {
+            x, y,
+            tags: vec![],
+            collidable: true,
+            sensor: false,
+            layer: DEFAULT_LAYER,
+            mask: ALL_LAYERS,
+            body: BodyKind::Dynamic,
+            one_way: None,
+            stype: ShapeType::ConvexPolygon(ConvexPolygon::new(points)?)
+        })
+    }
+
+    // Children are positioned relative to the compound, so moving the compound moves them all.
     pub fn new_compound(shapes: Vec<Shape>) -> Shape {
         Shape {
             x: 0.0, y: 0.0,
             tags: vec![],
             collidable: true,
+            sensor: false,
+            layer: DEFAULT_LAYER,
+            mask: ALL_LAYERS,
+            body: BodyKind::Dynamic,
+            one_way: None,
             stype: ShapeType::Compound(Compound(shapes))
         }
     }
 
     pub fn tags(&self) -> &Vec<String> { &self.tags }
     pub fn tags_mut(&mut self) -> &mut Vec<String> { &mut self.tags }
-    pub fn has_tags(&mut self, tags_to_query: &[impl AsRef<str>]) -> bool {
+    pub fn has_tags(&self, tags_to_query: &[impl AsRef<str>]) -> bool {
         tags_to_query.iter().all(|x| self.tags.contains(&x.as_ref().to_string()))
     }
 
-    fn aabb_aabb(a: &Shape, a_t: &AABB, b: &Shape, b_t: &AABB) -> Option<Vector2<f32>> {
-        let a_edges = a_t.generate_edges(a);
-        let b_edges = b_t.generate_edges(b);
-        let a_points = a_t.generate_points(a);
-        let b_points = b_t.generate_points(b);
+    // The points of any convex shape in world space, for the SAT tests.
+    fn convex_points(&self) -> Option<Vec<Point2<f32>>> {
+        match self.stype {
+            ShapeType::AABB(ref aabb) => Some(aabb.generate_points(self)),
+            ShapeType::ConvexPolygon(ref polygon) => Some(polygon.generate_points(self)),
+            _ => None,
+        }
+    }
+
+    fn convex_convex(a_points: Vec<Point2<f32>>, b_points: Vec<Point2<f32>>) -> Option<Vector2<f32>> {
+        let a_edges = generate_edges(a_points.clone());
+        let b_edges = generate_edges(b_points.clone());
 
         let edges: Vec<_> = a_edges.into_iter().chain(b_edges.into_iter()).collect();
 
@@ -200,26 +346,122 @@ impl Shape {
         }
     }
 
-    fn aabb_compound(a: &Shape, a_t: &AABB, b: &Shape, b_t: &Compound) -> Option<Vector2<f32>> {
-        b_t.0.iter().find_map(|x| a.is_colliding(x))
+    fn circle_convex(a: &Shape, a_t: &Circle, b_points: Vec<Point2<f32>>) -> Option<Vector2<f32>> {
+        let center = Point2::new(a.x, a.y);
+        let closest = b_points.iter()
+            .min_by(|l, r| (*l - center).magnitude2().partial_cmp(&(*r - center).magnitude2()).unwrap_or(Ordering::Equal))
+            .cloned()
+            .unwrap_or(center);
+
+        // A circle has no edges of its own, so the only extra axis is the one towards the nearest corner.
+        let mut axes: Vec<_> = generate_edges(b_points.clone()).into_iter().map(|x| orthogonal(x)).collect();
+        axes.push(closest - center);
+
+        let mut mpv: Option<Vector2<f32>> = None;
+        for o in axes.into_iter().filter(|x| x.magnitude2() > 0.0).map(|x| x.normalize()) {
+            let c = dot(center - Point2::new(0.0, 0.0), o);
+            let (min1, max1) = (c - a_t.r, c + a_t.r);
+            let (mut min2, mut max2) = (std::f32::MAX, std::f32::MIN);
+            for v in b_points.iter() {
+                let proj = dot(v - Point2::new(0.0, 0.0), o);
+                min2 = min2.min(proj);
+                max2 = max2.max(proj);
+            }
+
+            if max1 <= min2 || max2 <= min1 {
+                return None
+            }
+            let pv = o * (max2 - min1).min(max1 - min2);
+            if mpv.map(|x| dot(pv, pv) < dot(x, x)).unwrap_or(true) {
+                mpv = Some(pv);
+            }
+        }
+
+        mpv.map(|mpv| {
+            use cgmath::EuclideanSpace;
+            if dot(Point2::centroid(&b_points) - center, mpv) > 0.0 { -mpv } else { mpv }
+        })
+    }
+
+    fn circle_circle(a: &Shape, a_t: &Circle, b: &Shape, b_t: &Circle) -> Option<Vector2<f32>> {
+        let d = Vector2::new(b.x - a.x, b.y - a.y);
+        let dist = d.magnitude();
+        let reach = a_t.r + b_t.r;
+
+        if dist >= reach {
+            None
+        } else if dist > 0.0 {
+            Some(-d / dist * (reach - dist))
+        } else {
+            // Same center, so there's no "away". Just pick a side.
+            Some(Vector2::new(-reach, 0.0))
+        }
+    }
+
+    fn circle_aabb(a: &Shape, a_t: &Circle, b: &Shape, b_t: &AABB) -> Option<Vector2<f32>> {
+        let center = Vector2::new(a.x, a.y);
+        let closest = Vector2::new(a.x.max(b.x).min(b.x + b_t.w), a.y.max(b.y).min(b.y + b_t.h));
+        let d = center - closest;
+        let dist = d.magnitude();
+
+        if dist > 0.0 {
+            if dist < a_t.r {
+                Some(d / dist * (a_t.r - dist))
+            } else {
+                None
+            }
+        } else {
+            // The center is inside the box, so push out through the nearest side.
+            let exits = [
+                (a.x - b.x, Vector2::new(-1.0, 0.0)),
+                (b.x + b_t.w - a.x, Vector2::new(1.0, 0.0)),
+                (a.y - b.y, Vector2::new(0.0, -1.0)),
+                (b.y + b_t.h - a.y, Vector2::new(0.0, 1.0)),
+            ];
+            exits.iter()
+                .min_by(|l, r| l.0.partial_cmp(&r.0).unwrap_or(Ordering::Equal))
+                .map(|&(depth, dir)| dir * (depth + a_t.r))
+        }
+    }
+
+    // Tests against every child and keeps the deepest push, so resolving it clears the worst overlap.
+    fn shape_compound(a: &Shape, b: &Shape, b_t: &Compound) -> Option<Vector2<f32>> {
+        b_t.0.iter()
+            .filter_map(|x| a.is_colliding(&x.shifted(b.x, b.y)))
+            .max_by(|l, r| dot(*l, *l).partial_cmp(&dot(*r, *r)).unwrap_or(Ordering::Equal))
     }
 
     pub fn is_colliding(&self, o: &Shape) -> Option<Vector2<f32>> {
         // Main colliding logic
         if self.collidable && o.collidable {
-            match self.stype {
-                ShapeType::AABB(ref aabb) => {
-                    match o.stype {
-                        ShapeType::AABB(ref aabb2) => {
-                            Shape::aabb_aabb(self, aabb, o, aabb2)
-                        },
-                        ShapeType::Compound(ref compound) => {
-                            Shape::aabb_compound(self, aabb, o, compound)
-                        },
-                    }
+            match (&self.stype, &o.stype) {
+                (ShapeType::AABB(_), ShapeType::AABB(_)) |
+                (ShapeType::AABB(_), ShapeType::ConvexPolygon(_)) |
+                (ShapeType::ConvexPolygon(_), ShapeType::AABB(_)) |
+                (ShapeType::ConvexPolygon(_), ShapeType::ConvexPolygon(_)) => {
+                    Shape::convex_convex(self.convex_points().unwrap(), o.convex_points().unwrap())
+                },
+                (ShapeType::Circle(ref circle), ShapeType::Circle(ref circle2)) => {
+                    Shape::circle_circle(self, circle, o, circle2)
+                },
+                (ShapeType::Circle(ref circle), ShapeType::AABB(ref aabb)) => {
+                    Shape::circle_aabb(self, circle, o, aabb)
+                },
+                (ShapeType::AABB(ref aabb), ShapeType::Circle(ref circle)) => {
+                    Shape::circle_aabb(o, circle, self, aabb).map(|x| -x)
+                },
+                (ShapeType::Circle(ref circle), ShapeType::ConvexPolygon(ref polygon)) => {
+                    Shape::circle_convex(self, circle, polygon.generate_points(o))
+                },
+                (ShapeType::ConvexPolygon(ref polygon), ShapeType::Circle(ref circle)) => {
+                    Shape::circle_convex(o, circle, polygon.generate_points(self)).map(|x| -x)
                 },
-                ShapeType::Compound(ref compound) => {
-                    None // TEMPORARY Until I'm unlazy
+                (_, ShapeType::Compound(ref compound)) => {
+                    Shape::shape_compound(self, o, compound)
+                },
+                // Flip it around so compounds are always on the right, then flip the push back
+                (ShapeType::Compound(ref compound), _) => {
+                    Shape::shape_compound(o, self, compound).map(|x| -x)
                 },
             }
         } else {
@@ -227,8 +469,66 @@ impl Shape {
         }
     }
 
+    // Top-left and bottom-right corners of the box that fully contains the shape.
+    pub fn bounds(&self) -> (Point2<f32>, Point2<f32>) {
+        match self.stype {
+            ShapeType::AABB(ref aabb) => {
+                (Point2::new(self.x, self.y), Point2::new(self.x + aabb.w, self.y + aabb.h))
+            },
+            ShapeType::Circle(ref circle) => {
+                (Point2::new(self.x - circle.r, self.y - circle.r), Point2::new(self.x + circle.r, self.y + circle.r))
+            },
+            ShapeType::ConvexPolygon(ref polygon) => {
+                polygon.generate_points(self).into_iter().fold(
+                    (Point2::new(std::f32::MAX, std::f32::MAX), Point2::new(std::f32::MIN, std::f32::MIN)),
+                    |(min, max), p| (Point2::new(min.x.min(p.x), min.y.min(p.y)), Point2::new(max.x.max(p.x), max.y.max(p.y))))
+            },
+            ShapeType::Compound(ref compound) => {
+                compound.0.iter().map(|x| x.shifted(self.x, self.y).bounds()).fold(
+                    (Point2::new(self.x, self.y), Point2::new(self.x, self.y)),
+                    |(min, max), (cmin, cmax)| (Point2::new(min.x.min(cmin.x), min.y.min(cmin.y)), Point2::new(max.x.max(cmax.x), max.y.max(cmax.y))))
+            },
+        }
+    }
+
     pub fn collidable(&self) -> bool { self.collidable }
     pub fn collidable_mut(&mut self) -> &mut bool { &mut self.collidable }
+    pub fn sensor(&self) -> bool { self.sensor }
+    pub fn sensor_mut(&mut self) -> &mut bool { &mut self.sensor }
+    pub fn layer(&self) -> u32 { self.layer }
+    pub fn layer_mut(&mut self) -> &mut u32 { &mut self.layer }
+    pub fn mask(&self) -> u32 { self.mask }
+    pub fn mask_mut(&mut self) -> &mut u32 { &mut self.mask }
+    pub fn with_layers(mut self, layer: u32, mask: u32) -> Shape {
+        self.layer = layer;
+        self.mask = mask;
+        self
+    }
+    pub fn body(&self) -> BodyKind { self.body }
+    pub fn body_mut(&mut self) -> &mut BodyKind { &mut self.body }
+    pub fn with_body(mut self, body: BodyKind) -> Shape {
+        self.body = body;
+        self
+    }
+    pub fn one_way(&self) -> Option<Vector2<f32>> { self.one_way }
+    pub fn one_way_mut(&mut self) -> &mut Option<Vector2<f32>> { &mut self.one_way }
+    pub fn with_one_way(mut self, direction: Vector2<f32>) -> Shape {
+        self.one_way = Some(direction);
+        self
+    }
+    // Whether this shape stops something running into it through the face with `normal` (facing back
+    // at whatever's coming in). A one-way shape only lets things in through the face opposite its
+    // direction, so its ends and its far side block even if the motion is a bit its way. Always true
+    // for normal shapes.
+    pub fn blocks(&self, normal: Vector2<f32>) -> bool {
+        self.one_way
+            .filter(|x| x.magnitude2() > 0.0 && normal.magnitude2() > 0.0)
+            .map(|x| dot(normal.normalize(), -x.normalize()) < ONE_WAY_MIN_ALIGNMENT)
+            .unwrap_or(true)
+    }
+    pub fn interacts_with(&self, o: &Shape) -> bool {
+        self.layer & o.mask != 0 && o.layer & self.mask != 0
+    }
     pub fn x(&self) -> f32 { self.x }
     pub fn x_mut(&mut self) -> &mut f32 { &mut self.x }
     pub fn y(&self) -> f32 { self.y }
@@ -242,17 +542,65 @@ impl Shape {
             y: self.y + dy,
             tags: self.tags.clone(),
             collidable: self.collidable,
-            stype: self.stype.shifted(dx, dy)
+            sensor: self.sensor,
+            layer: self.layer,
+            mask: self.mask,
+            body: self.body,
+            one_way: self.one_way,
+            stype: self.stype.clone()
         }
     }
 }
 
 pub type ShapeIndex = GenerationalIndex;
+
+// Files the shape in the grid for its body kind, taking it out of the other one in case that changed.
+fn file_shape(index: ShapeIndex, shape: &Shape, broadphase: &mut SpatialHash, statics: &mut SpatialHash) {
+    let (min, max) = shape.bounds();
+    if shape.body == BodyKind::Static {
+        broadphase.remove(index);
+        statics.update(index, min, max);
+    } else {
+        statics.remove(index);
+        broadphase.update(index, min, max);
+    }
+}
+
+// A shape borrowed out of a Space for editing. Once it's dropped the shape gets re-filed
+// in the broadphase, so moving shapes around keeps the grid up to date by itself.
+pub struct ShapeMut<'a> {
+    index: ShapeIndex,
+    shape: &'a mut Shape,
+    broadphase: &'a mut SpatialHash,
+    statics: &'a mut SpatialHash,
+}
+
+impl<'a> Deref for ShapeMut<'a> {
+    type Target = Shape;
+    fn deref(&self) -> &Shape { self.shape }
+}
+
+impl<'a> DerefMut for ShapeMut<'a> {
+    fn deref_mut(&mut self) -> &mut Shape { self.shape }
+}
+
+impl<'a> Drop for ShapeMut<'a> {
+    fn drop(&mut self) {
+        file_shape(self.index, self.shape, self.broadphase, self.statics);
+    }
+}
+
+pub const DEFAULT_CELL_SIZE: f32 = 64.0;
+
 #[derive(Debug)]
 pub struct Space {
     shapes: Vec<GenerationalIndex>,
     shapes_array: GenerationalIndexArray<Shape>,
     shapes_allocator: GenerationalIndexAllocator,
+    // Everything that isn't static.
+    broadphase: SpatialHash,
+    // Static shapes only. Uses the same cell size, so a cell means the same thing in both.
+    statics: SpatialHash,
 }
 
 impl Default for Space {
@@ -263,15 +611,23 @@ impl Default for Space {
 
 impl Space {
     pub fn new() -> Space {
+        Space::with_cell_size(DEFAULT_CELL_SIZE)
+    }
+
+    // Cells should be around the size of the typical shape; a couple of tiles is a good start.
+    pub fn with_cell_size(cell_size: f32) -> Space {
         Space {
             shapes: vec![],
             shapes_array: GenerationalIndexArray::new(),
             shapes_allocator: GenerationalIndexAllocator::new(),
+            broadphase: SpatialHash::new(cell_size),
+            statics: SpatialHash::new(cell_size),
         }
     }
 
     pub fn add_shape(&mut self, shape: Shape) -> ShapeIndex {
         let ni = self.shapes_allocator.allocate();
+        file_shape(ni, &shape, &mut self.broadphase, &mut self.statics);
         self.shapes.push(ni);
         self.shapes_array.set(ni, shape);
         ni
@@ -280,28 +636,69 @@ impl Space {
     pub fn remove_shape(&mut self, shape_index: ShapeIndex) {
         self.shapes_allocator.deallocate(shape_index);
         self.shapes.retain(|&x| x != shape_index);
+        self.broadphase.remove(shape_index);
+        self.statics.remove(shape_index);
     }
 
     pub fn shape(&self, shape_index: ShapeIndex) -> Option<&Shape> {
-        self.shapes_array.get(shape_index)
+        self.shapes_array.get(shape_index).filter(|_| self.shapes_allocator.is_live(shape_index))
+    }
+
+    // Removed shapes are still sitting in shapes_array, and dropping the guard would file them back
+    // in the grid, so they have to be turned away here.
+    pub fn shape_mut(&mut self, shape_index: ShapeIndex) -> Option<ShapeMut<'_>> {
+        if !self.shapes_allocator.is_live(shape_index) {
+            return None
+        }
+        let (broadphase, statics) = (&mut self.broadphase, &mut self.statics);
+        self.shapes_array.get_mut(shape_index).map(move |shape| ShapeMut {
+            index: shape_index,
+            shape,
+            broadphase,
+            statics,
+        })
+    }
+
+    // Every shape filed near the bounds, static or not. Each one only lives in one grid, so no duplicates.
+    // Bounds covering more cells than there are shapes are quicker to just check against everything.
+    fn candidates(&self, min: Point2<f32>, max: Point2<f32>) -> Vec<ShapeIndex> {
+        if self.broadphase.cell_count(min, max) > self.shapes.len() as u64 {
+            return self.shapes.clone()
+        }
+        let mut found = self.broadphase.query(min, max);
+        found.extend(self.statics.query(min, max));
+        found
     }
 
-    pub fn shape_mut(&mut self, shape_index: ShapeIndex) -> Option<&mut Shape> {
-        self.shapes_array.get_mut(shape_index)
+    // The shapes in a single grid cell, static or not.
+    fn cell(&self, cell: (i32, i32)) -> impl Iterator<Item=&ShapeIndex> {
+        self.broadphase.cell(cell).iter().chain(self.statics.cell(cell).iter())
     }
 
     pub fn check_collisions(&mut self, shape_index: ShapeIndex) -> Vec<Collision> {
         if self.shapes_allocator.is_live(shape_index) {
             let shape = self.shapes_array.get(shape_index).unwrap();
+            let (min, max) = shape.bounds();
+            // Static shapes never need testing against each other.
+            let candidates = if shape.body == BodyKind::Static {
+                self.broadphase.query(min, max)
+            } else {
+                self.candidates(min, max)
+            };
             let mut colls = vec![];
-            for si in self.shapes.iter().filter(|&x| *x != shape_index) {
+            for si in candidates.iter().filter(|&x| *x != shape_index) {
                 let other = self.shapes_array.get(*si).unwrap();
+                if !shape.interacts_with(other) {
+                    continue
+                }
                 if let Some(mpv) = shape.is_colliding(other) {
                     colls.push(Collision {
                         resolve_x: mpv.x,
                         resolve_y: mpv.y,
                         shape_a: shape_index,
                         shape_b: *si,
+                        sensor: shape.sensor || other.sensor,
+                        one_way: shape.one_way.is_some() || other.one_way.is_some(),
                     });
                 }
             }
@@ -310,4 +707,262 @@ impl Space {
             vec![]
         }
     }
-}
\ No newline at end of file
+
+    // The old test-everything path, kept to check the broadphase against.
+    #[cfg(test)]
+    fn check_collisions_brute_force(&self, shape_index: ShapeIndex) -> Vec<Collision> {
+        let shape = self.shapes_array.get(shape_index).unwrap();
+        let mut colls = vec![];
+        for si in self.shapes.iter().filter(|&x| *x != shape_index) {
+            let other = self.shapes_array.get(*si).unwrap();
+            if !shape.interacts_with(other) || (shape.body == BodyKind::Static && other.body == BodyKind::Static) {
+                continue
+            }
+            if let Some(mpv) = shape.is_colliding(other) {
+                colls.push(Collision {
+                    resolve_x: mpv.x,
+                    resolve_y: mpv.y,
+                    shape_a: shape_index,
+                    shape_b: *si,
+                    sensor: shape.sensor || other.sensor,
+                    one_way: shape.one_way.is_some() || other.one_way.is_some(),
+                });
+            }
+        }
+        colls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Small LCG so the test map is the same every run without pulling in rand.
+    pub(super) struct Lcg(pub(super) u64);
+
+    impl Lcg {
+        pub(super) fn next(&mut self, max: f32) -> f32 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32 * max
+        }
+    }
+
+    fn random_shape(rng: &mut Lcg) -> Shape {
+        let (x, y) = (rng.next(4096.0), rng.next(4096.0));
+        match (rng.next(4.0)) as u32 {
+            0 => Shape::new_circle(x, y, 4.0 + rng.next(28.0)),
+            1 => Shape::new_convex_polygon(x, y, vec![
+                Point2::new(0.0, 0.0), Point2::new(8.0 + rng.next(40.0), 0.0), Point2::new(0.0, 8.0 + rng.next(40.0))
+            ]).unwrap(),
+            2 => {
+                let mut compound = Shape::new_compound(vec![
+                    Shape::new_rectangle_xywh(0.0, 0.0, 48.0, 16.0),
+                    Shape::new_rectangle_xywh(0.0, 0.0, 16.0, 48.0),
+                ]);
+                compound.shift(x, y);
+                compound
+            },
+            _ => Shape::new_rectangle_xywh(x, y, 4.0 + rng.next(60.0), 4.0 + rng.next(60.0)),
+        }
+    }
+
+    fn same_collisions(a: &[Collision], b: &[Collision]) -> bool {
+        let key = |c: &Collision| (c.shape_b.index(), c.shape_b.generation());
+        let mut a: Vec<_> = a.iter().map(|c| (key(c), c.resolve_x, c.resolve_y)).collect();
+        let mut b: Vec<_> = b.iter().map(|c| (key(c), c.resolve_x, c.resolve_y)).collect();
+        a.sort_by(|l, r| l.0.cmp(&r.0));
+        b.sort_by(|l, r| l.0.cmp(&r.0));
+        a == b
+    }
+
+    // Shared with the submodules' tests.
+    pub(super) fn close(a: Vector2<f32>, b: Vector2<f32>) -> bool {
+        (a - b).magnitude() < 1e-4
+    }
+
+    // Tests the pair both ways round, checks the pushes are equal and opposite, and gives back a's.
+    fn push_both_ways(a: &Shape, b: &Shape) -> Vector2<f32> {
+        let ab = a.is_colliding(b).expect("a should hit b");
+        let ba = b.is_colliding(a).expect("b should hit a");
+        assert!(close(ab, -ba), "{:?} and {:?} aren't opposite", ab, ba);
+        ab
+    }
+
+    // Two boxes side by side, with a gap between them.
+    fn two_boxes(x: f32, y: f32) -> Shape {
+        let mut compound = Shape::new_compound(vec![
+            Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0),
+            Shape::new_rectangle_xywh(20.0, 0.0, 10.0, 10.0),
+        ]);
+        compound.shift(x, y);
+        compound
+    }
+
+    #[test]
+    fn compound_vs_aabb() {
+        // Overlaps the left child by 1 and the right one by 3. The right one should win.
+        let compound = two_boxes(100.0, 100.0);
+        let aabb = Shape::new_rectangle_xywh(109.0, 102.0, 14.0, 4.0);
+
+        let push = push_both_ways(&aabb, &compound);
+        assert!(close(push, Vector2::new(-3.0, 0.0)), "{:?}", push);
+        let deepest = Shape::new_rectangle_xywh(120.0, 100.0, 10.0, 10.0);
+        assert!(close(push, aabb.is_colliding(&deepest).unwrap()));
+
+        assert!(close(compound.is_colliding(&aabb).unwrap(), Vector2::new(3.0, 0.0)));
+    }
+
+    #[test]
+    fn compound_vs_compound() {
+        // b's left child overlaps a's left child by 1, b's right child overlaps a's right child by 2.
+        let a = two_boxes(0.0, 0.0);
+        let mut b = Shape::new_compound(vec![
+            Shape::new_rectangle_xywh(0.0, 0.0, 4.0, 4.0),
+            Shape::new_rectangle_xywh(9.0, 0.0, 4.0, 4.0),
+        ]);
+        b.shift(9.0, 2.0);
+
+        let push = push_both_ways(&b, &a);
+        assert!(close(push, Vector2::new(-2.0, 0.0)), "{:?}", push);
+        let (b_child, a_child) = (Shape::new_rectangle_xywh(18.0, 2.0, 4.0, 4.0), Shape::new_rectangle_xywh(20.0, 0.0, 10.0, 10.0));
+        assert!(close(push, b_child.is_colliding(&a_child).unwrap()));
+    }
+
+    #[test]
+    fn compound_misses_through_gap() {
+        let compound = two_boxes(0.0, 0.0);
+        let aabb = Shape::new_rectangle_xywh(12.0, 2.0, 4.0, 4.0);
+        assert!(compound.is_colliding(&aabb).is_none());
+        assert!(aabb.is_colliding(&compound).is_none());
+    }
+
+    #[test]
+    fn circle_vs_circle() {
+        let (a, b) = (Shape::new_circle(0.0, 0.0, 5.0), Shape::new_circle(6.0, 8.0, 7.0));
+        assert!(close(push_both_ways(&a, &b), Vector2::new(-1.2, -1.6)));
+
+        // Just touching isn't a collision.
+        assert!(a.is_colliding(&Shape::new_circle(10.0, 0.0, 5.0)).is_none());
+
+        // Stacked exactly on top of each other there's no way to tell which way is out, but it still has to be out.
+        let push = a.is_colliding(&Shape::new_circle(0.0, 0.0, 3.0)).unwrap();
+        assert!((push.magnitude() - 8.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn circle_vs_aabb() {
+        let aabb = Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0);
+
+        // Off a side, and off a corner.
+        assert!(close(push_both_ways(&Shape::new_circle(15.0, 5.0, 6.0), &aabb), Vector2::new(1.0, 0.0)));
+        assert!(close(push_both_ways(&Shape::new_circle(13.0, 14.0, 6.0), &aabb), Vector2::new(0.6, 0.8)));
+
+        // Center inside the box goes out the nearest side, all the way clear.
+        assert!(close(push_both_ways(&Shape::new_circle(8.0, 5.0, 2.0), &aabb), Vector2::new(4.0, 0.0)));
+
+        assert!(Shape::new_circle(20.0, 5.0, 5.0).is_colliding(&aabb).is_none());
+        assert!(aabb.is_colliding(&Shape::new_circle(20.0, 5.0, 5.0)).is_none());
+    }
+
+    #[test]
+    fn circle_vs_compound() {
+        // In the gap, 3 into the left box and 1 into the right. The left one should win.
+        let compound = two_boxes(0.0, 0.0);
+        let push = push_both_ways(&Shape::new_circle(14.0, 5.0, 7.0), &compound);
+        assert!(close(push, Vector2::new(3.0, 0.0)), "{:?}", push);
+
+        // Off the corner of the right box.
+        assert!(close(push_both_ways(&Shape::new_circle(33.0, 14.0, 6.0), &compound), Vector2::new(0.6, 0.8)));
+
+        // Small enough to sit in the gap without touching either.
+        assert!(Shape::new_circle(15.0, 5.0, 4.0).is_colliding(&compound).is_none());
+        assert!(compound.is_colliding(&Shape::new_circle(15.0, 5.0, 4.0)).is_none());
+    }
+
+    fn square_points() -> Vec<Point2<f32>> {
+        vec![Point2::new(0.0, 0.0), Point2::new(10.0, 0.0), Point2::new(10.0, 10.0), Point2::new(0.0, 10.0)]
+    }
+
+    #[test]
+    fn polygon_vs_shapes() {
+        let square = Shape::new_convex_polygon(0.0, 0.0, square_points()).unwrap();
+        let triangle = Shape::new_convex_polygon(0.0, 0.0, vec![Point2::new(0.0, 0.0), Point2::new(10.0, 0.0), Point2::new(0.0, 10.0)]).unwrap();
+
+        // A square polygon should act just like the box it looks like.
+        let aabb = Shape::new_rectangle_xywh(8.0, 2.0, 10.0, 4.0);
+        assert!(close(push_both_ways(&square, &aabb), Vector2::new(-2.0, 0.0)));
+        assert!(close(push_both_ways(&square, &aabb), Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0).is_colliding(&aabb).unwrap()));
+
+        // Out through the triangle's slanted side.
+        let circle = Shape::new_circle(6.0, 6.0, 2.0);
+        let out = 2.0 - 2.0f32.sqrt();
+        assert!(close(push_both_ways(&circle, &triangle), Vector2::new(1.0, 1.0).normalize() * out));
+        assert!(Shape::new_circle(8.0, 8.0, 2.0).is_colliding(&triangle).is_none());
+
+        // Polygon against polygon: applying the push has to actually separate them.
+        let mut other = Shape::new_convex_polygon(4.0, 4.0, vec![Point2::new(0.0, 0.0), Point2::new(8.0, -2.0), Point2::new(6.0, 6.0)]).unwrap();
+        let push = push_both_ways(&other, &triangle);
+        other.shift(push.x * 1.01, push.y * 1.01);
+        assert!(other.is_colliding(&triangle).is_none());
+    }
+
+    #[test]
+    fn polygon_winding_doesnt_matter() {
+        let mut clockwise = square_points();
+        clockwise.reverse();
+        let (ccw, cw) = (Shape::new_convex_polygon(0.0, 0.0, square_points()).unwrap(), Shape::new_convex_polygon(0.0, 0.0, clockwise).unwrap());
+        let aabb = Shape::new_rectangle_xywh(7.0, 3.0, 10.0, 2.0);
+        assert!(close(ccw.is_colliding(&aabb).unwrap(), cw.is_colliding(&aabb).unwrap()));
+    }
+
+    #[test]
+    fn bad_polygons_are_rejected() {
+        let polygon = |points: Vec<(f32, f32)>| Shape::new_convex_polygon(0.0, 0.0, points.into_iter().map(|(x, y)| Point2::new(x, y)).collect());
+
+        assert!(polygon(vec![(0.0, 0.0), (10.0, 0.0)]).is_err());
+        // No area.
+        assert!(polygon(vec![(0.0, 0.0), (5.0, 5.0), (10.0, 10.0)]).is_err());
+        // Dented in.
+        assert!(polygon(vec![(0.0, 0.0), (10.0, 0.0), (5.0, 3.0), (10.0, 10.0), (0.0, 10.0)]).is_err());
+        // The same point twice in a row, including across the wrap back to the start.
+        assert!(polygon(vec![(0.0, 0.0), (10.0, 0.0), (10.0, 0.0), (10.0, 10.0)]).is_err());
+        assert!(polygon(vec![(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 0.0)]).is_err());
+        // A pentagram turns the same way at every corner, but goes round twice.
+        let star = (0..5).map(|i| {
+            let angle = (i * 2 % 5) as f32 * 2.0 * std::f32::consts::PI / 5.0;
+            (angle.cos() * 10.0, angle.sin() * 10.0)
+        }).collect();
+        assert!(polygon(star).is_err());
+    }
+
+    #[test]
+    fn broadphase_matches_brute_force() {
+        let mut rng = Lcg(0x5eed);
+        let mut space = Space::new();
+        // Every fifth one is static, so static-static pairs get skipped and the two grids both get queried.
+        let indices: Vec<_> = (0..4000).map(|i| {
+            let shape = random_shape(&mut rng);
+            space.add_shape(if i % 5 == 0 { shape.with_body(BodyKind::Static) } else { shape })
+        }).collect();
+
+        // Move a chunk of them around through shape_mut, and drop a few, so the incremental updates get exercised too.
+        for &si in indices.iter().step_by(3) {
+            let (dx, dy) = (rng.next(256.0) - 128.0, rng.next(256.0) - 128.0);
+            space.shape_mut(si).unwrap().shift(dx, dy);
+        }
+        // Moving a removed shape can't sneak it back into the grid.
+        for &si in indices.iter().step_by(50) {
+            space.remove_shape(si);
+            assert!(space.shape(si).is_none());
+            assert!(space.shape_mut(si).is_none());
+        }
+
+        // Brute force is the slow part, so only check a sample of the shapes.
+        let live: Vec<_> = indices.into_iter().filter(|&x| space.shapes_allocator.is_live(x)).step_by(7).collect();
+        for si in live {
+            let broad = space.check_collisions(si);
+            let brute = space.check_collisions_brute_force(si);
+            assert!(same_collisions(&broad, &brute), "broadphase disagrees for {:?}", si);
+        }
+    }
+}
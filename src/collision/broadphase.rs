@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use cgmath::{Point2, Vector2};
+use super::ShapeIndex;
+
+type CellRange = (i32, i32, i32, i32);
+
+// Uniform grid: every shape is filed under each cell its bounding box touches,
+// so a query only has to look at the shapes sharing its cells.
+#[derive(Debug)]
+pub struct SpatialHash {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<ShapeIndex>>,
+    shape_cells: HashMap<ShapeIndex, CellRange>,
+}
+
+impl SpatialHash {
+    pub fn new(cell_size: f32) -> SpatialHash {
+        SpatialHash {
+            cell_size,
+            cells: HashMap::new(),
+            shape_cells: HashMap::new(),
+        }
+    }
+
+    pub fn cell_size(&self) -> f32 { self.cell_size }
+
+    fn cell_range(&self, min: Point2<f32>, max: Point2<f32>) -> CellRange {
+        ((min.x / self.cell_size).floor() as i32,
+         (min.y / self.cell_size).floor() as i32,
+         (max.x / self.cell_size).floor() as i32,
+         (max.y / self.cell_size).floor() as i32)
+    }
+
+    // How many cells the bounds cover. Can be far more than fit in an i32 for a big enough rectangle.
+    pub fn cell_count(&self, min: Point2<f32>, max: Point2<f32>) -> u64 {
+        let (x1, y1, x2, y2) = self.cell_range(min, max);
+        ((x2 as i64 - x1 as i64 + 1).max(0) as u64).saturating_mul((y2 as i64 - y1 as i64 + 1).max(0) as u64)
+    }
+
+    fn cells_in(range: CellRange) -> impl Iterator<Item=(i32, i32)> {
+        let (x1, y1, x2, y2) = range;
+        (x1..=x2).flat_map(move |x| (y1..=y2).map(move |y| (x, y)))
+    }
+
+    // Files the shape under its new bounds. Cheap when it hasn't left its cells.
+    pub fn update(&mut self, index: ShapeIndex, min: Point2<f32>, max: Point2<f32>) {
+        let range = self.cell_range(min, max);
+        if self.shape_cells.get(&index) == Some(&range) {
+            return
+        }
+
+        self.remove(index);
+        for cell in SpatialHash::cells_in(range) {
+            self.cells.entry(cell).or_default().push(index);
+        }
+        self.shape_cells.insert(index, range);
+    }
+
+    pub fn remove(&mut self, index: ShapeIndex) {
+        if let Some(range) = self.shape_cells.remove(&index) {
+            for cell in SpatialHash::cells_in(range) {
+                if let Some(bucket) = self.cells.get_mut(&cell) {
+                    bucket.retain(|&x| x != index);
+                    if bucket.is_empty() {
+                        self.cells.remove(&cell);
+                    }
+                }
+            }
+        }
+    }
+
+    // Every shape sharing a cell with the given bounds, each once, in a stable order.
+    pub fn query(&self, min: Point2<f32>, max: Point2<f32>) -> Vec<ShapeIndex> {
+        let mut found: Vec<_> = SpatialHash::cells_in(self.cell_range(min, max))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flat_map(|bucket| bucket.iter().cloned())
+            .collect();
+        found.sort_by_key(|x| (x.index(), x.generation()));
+        found.dedup();
+        found
+    }
+
+    pub fn cell(&self, cell: (i32, i32)) -> &[ShapeIndex] {
+        self.cells.get(&cell).map(|x| x.as_slice()).unwrap_or(&[])
+    }
+
+    // Walks the grid along a ray (Amanatides & Woo), giving each cell it passes through
+    // along with the distance at which the ray enters it. `dir` should be normalized.
+    // Cells are worked out as they're asked for, so stopping early costs nothing.
+    pub fn ray_cells(&self, origin: Point2<f32>, dir: Vector2<f32>, max_dist: f32) -> impl Iterator<Item=((i32, i32), f32)> {
+        let cell_size = self.cell_size;
+        let mut cell = ((origin.x / cell_size).floor() as i32, (origin.y / cell_size).floor() as i32);
+        let axis = |o: f32, d: f32, c: i32| -> (i32, f32, f32) {
+            if d > 0.0 {
+                (1, ((c + 1) as f32 * cell_size - o) / d, cell_size / d)
+            } else if d < 0.0 {
+                (-1, (c as f32 * cell_size - o) / d, -cell_size / d)
+            } else {
+                (0, std::f32::INFINITY, std::f32::INFINITY)
+            }
+        };
+        let (step_x, mut next_x, delta_x) = axis(origin.x, dir.x, cell.0);
+        let (step_y, mut next_y, delta_y) = axis(origin.y, dir.y, cell.1);
+
+        let mut started = false;
+        std::iter::from_fn(move || {
+            if !started {
+                started = true;
+                return Some((cell, 0.0))
+            }
+
+            let t = next_x.min(next_y);
+            if t > max_dist || !t.is_finite() {
+                return None
+            }
+            if next_x < next_y {
+                cell.0 += step_x;
+                next_x += delta_x;
+            } else {
+                cell.1 += step_y;
+                next_y += delta_y;
+            }
+            Some((cell, t))
+        })
+    }
+}
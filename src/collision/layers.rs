@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+// Named collision layers, read from a config file of `name = bit` lines (bits 0 to 31),
+// so gameplay code can say "ghost" instead of remembering which bit that was.
+#[derive(Debug, Clone, Default)]
+pub struct CollisionLayers {
+    layers: HashMap<String, u32>,
+}
+
+impl CollisionLayers {
+    pub fn parse(source: &str) -> Result<CollisionLayers, String> {
+        let mut layers = HashMap::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.split("//").next().unwrap().trim();
+            if line.is_empty() {
+                continue
+            }
+
+            let mut parts = line.splitn(2, '=').map(|x| x.trim());
+            let (name, bit) = match (parts.next(), parts.next()) {
+                (Some(name), Some(bit)) if !name.is_empty() => (name, bit),
+                _ => return Err(format!("Line {}: expected `name = bit`, got {:?}", i + 1, line)),
+            };
+            let bit: u32 = bit.parse().map_err(|e| format!("Line {}: bad bit for layer {:?}: {:?}", i + 1, name, e))?;
+            if bit >= 32 {
+                return Err(format!("Line {}: layer {:?} uses bit {}, but there are only 32", i + 1, name, bit));
+            }
+            if layers.insert(name.to_string(), 1 << bit).is_some() {
+                return Err(format!("Line {}: layer {:?} is defined twice", i + 1, name));
+            }
+        }
+
+        Ok(CollisionLayers {
+            layers,
+        })
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<CollisionLayers, String> {
+        std::fs::read_to_string(path.as_ref())
+            .map_err(|e| format!("Error loading collision layers: {:?}", e))
+            .and_then(|x| CollisionLayers::parse(&x))
+    }
+
+    // The bit for a single layer.
+    pub fn layer(&self, name: &str) -> Option<u32> {
+        self.layers.get(name).cloned()
+    }
+
+    // All the named layers together, e.g. for a mask. None if any of them isn't defined.
+    pub fn mask(&self, names: &[impl AsRef<str>]) -> Option<u32> {
+        names.iter().try_fold(0, |acc, x| self.layer(x.as_ref()).map(|bit| acc | bit))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Shape, Space, BodyKind};
+
+    const SOURCE: &str = "
+        // Comments and blank lines are skipped.
+
+        wall = 1
+        player=2 // trailing comments too
+        ghost = 31
+    ";
+
+    #[test]
+    fn parse_and_look_up() {
+        let layers = CollisionLayers::parse(SOURCE).unwrap();
+        assert_eq!(layers.layer("wall"), Some(1 << 1));
+        assert_eq!(layers.layer("player"), Some(1 << 2));
+        assert_eq!(layers.layer("ghost"), Some(1 << 31));
+        assert_eq!(layers.layer("npc"), None);
+
+        assert_eq!(layers.mask(&["wall", "player"]), Some(0b110));
+        assert_eq!(layers.mask(&[] as &[&str]), Some(0));
+        assert_eq!(layers.mask(&["wall", "npc"]), None);
+    }
+
+    #[test]
+    fn bad_sources_are_rejected() {
+        let error = |source: &str| CollisionLayers::parse(source).unwrap_err();
+
+        assert!(error("wall 1").starts_with("Line 1:"));
+        assert!(error("wall = 1\n= 2").starts_with("Line 2:"));
+        assert!(error("wall = one").contains("bad bit"));
+        assert!(error("wall = -1").contains("bad bit"));
+        assert!(error("wall = 32").contains("only 32"));
+        assert!(error("wall = 1\nfloor = 2\nwall = 3").starts_with("Line 3:"));
+        assert!(error("wall = 1\nwall = 1").contains("defined twice"));
+    }
+
+    #[test]
+    fn one_sided_masks() {
+        // Ghosts drift through walls, but still bump into players. Walls don't mask ghosts out themselves;
+        // it only takes one side leaving the other out.
+        let layers = CollisionLayers::parse(SOURCE).unwrap();
+        let (wall, player, ghost) = (layers.layer("wall").unwrap(), layers.layer("player").unwrap(), layers.layer("ghost").unwrap());
+
+        let mut space = Space::new();
+        let w = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0).with_layers(wall, layers.mask(&["player", "ghost"]).unwrap()).with_body(BodyKind::Static));
+        let p = space.add_shape(Shape::new_rectangle_xywh(8.0, 0.0, 10.0, 10.0).with_layers(player, layers.mask(&["wall", "ghost"]).unwrap()));
+        let g = space.add_shape(Shape::new_rectangle_xywh(5.0, 0.0, 10.0, 10.0).with_layers(ghost, player));
+
+        let hits = |space: &mut Space, si| space.check_collisions(si).iter().map(|x| x.shape_b).collect::<Vec<_>>();
+        assert_eq!(hits(&mut space, g), vec![p]);
+        assert_eq!(hits(&mut space, p).len(), 2);
+        assert!(!hits(&mut space, w).contains(&g));
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use cgmath::{prelude::*, Vector2, dot};
+use super::{ShapeIndex, Space, CONTACT_SKIN};
+
+// How many times one push can run into something and carry on before giving up on the rest of it.
+const MAX_PUSH_STEPS: usize = 4;
+
+impl Space {
+    // Shoves a pushable shape (one in `masses`) along `by`, as hard as something weighing `pusher_mass`
+    // can: the heavier the shape is next to the pusher, the less of `by` it takes. It stops at whatever's
+    // in the way, unless that's pushable too, in which case that gets pushed in turn and followed, for up to
+    // `depth` shapes in a row. Gives back how far the shape got, which is nothing if it isn't pushable.
+    // Pushed shapes don't slide, they just stop.
+    pub fn push(&mut self, shape_index: ShapeIndex, by: Vector2<f32>, pusher_mass: f32, masses: &HashMap<ShapeIndex, f32>, depth: usize) -> Vector2<f32> {
+        let mass = match masses.get(&shape_index) {
+            Some(&mass) if depth > 0 && self.shapes_allocator.is_live(shape_index) => mass,
+            _ => return Vector2::new(0.0, 0.0),
+        };
+        let mut moved = Vector2::new(0.0, 0.0);
+        let mut remaining = by * (pusher_mass / (pusher_mass + mass));
+
+        for _ in 0..MAX_PUSH_STEPS {
+            if remaining.magnitude2() == 0.0 {
+                break
+            }
+
+            let hit = self.sweep(shape_index, remaining);
+            let travel = if let Some(ref hit) = hit {
+                remaining * hit.time + hit.normal * CONTACT_SKIN
+            } else {
+                remaining
+            };
+            self.shape_mut(shape_index).unwrap().shift(travel.x, travel.y);
+            moved += travel;
+
+            match hit {
+                // Whatever it can't pass on to the next one in line, it can't do itself either.
+                Some(hit) => {
+                    let into = hit.normal * dot(remaining * (1.0 - hit.time), hit.normal);
+                    remaining = self.push(hit.shape, into, mass, masses, depth - 1);
+                },
+                None => break,
+            }
+        }
+
+        moved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Shape, BodyKind};
+    use super::super::tests::close;
+
+    // 10x10 boxes in a row along x, starting at the origin, each one resting CONTACT_SKIN off the last.
+    fn row(space: &mut Space, count: usize) -> Vec<ShapeIndex> {
+        (0..count).map(|i| space.add_shape(Shape::new_rectangle_xywh(i as f32 * (10.0 + CONTACT_SKIN), 0.0, 10.0, 10.0))).collect()
+    }
+
+    #[test]
+    fn split_by_mass() {
+        let mut space = Space::new();
+        let boulder = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0));
+        let masses: HashMap<_, _> = vec![(boulder, 3.0)].into_iter().collect();
+
+        // Three times as heavy as the pusher, so it only takes a quarter.
+        assert!(close(space.push(boulder, Vector2::new(8.0, 0.0), 1.0, &masses, 1), Vector2::new(2.0, 0.0)));
+        assert!(close(space.push(boulder, Vector2::new(0.0, -8.0), 3.0, &masses, 1), Vector2::new(0.0, -4.0)));
+        assert_eq!((space.shape(boulder).unwrap().x(), space.shape(boulder).unwrap().y()), (2.0, -4.0));
+    }
+
+    #[test]
+    fn only_pushable_shapes_move() {
+        let mut space = Space::new();
+        let wall = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0).with_body(BodyKind::Static));
+        let boulder = space.add_shape(Shape::new_rectangle_xywh(50.0, 0.0, 10.0, 10.0));
+        let masses: HashMap<_, _> = vec![(boulder, 1.0)].into_iter().collect();
+
+        assert_eq!(space.push(wall, Vector2::new(8.0, 0.0), 1.0, &masses, 3), Vector2::new(0.0, 0.0));
+        assert_eq!(space.push(boulder, Vector2::new(8.0, 0.0), 1.0, &masses, 0), Vector2::new(0.0, 0.0));
+        assert_eq!(space.shape(wall).unwrap().x(), 0.0);
+        assert_eq!(space.shape(boulder).unwrap().x(), 50.0);
+    }
+
+    #[test]
+    fn stops_at_walls() {
+        let mut space = Space::new();
+        let boulder = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0));
+        space.add_shape(Shape::new_rectangle_xywh(15.0, -20.0, 10.0, 50.0).with_body(BodyKind::Static));
+        let masses: HashMap<_, _> = vec![(boulder, 1.0)].into_iter().collect();
+
+        // Wants to go 10, only has room for 5.
+        let moved = space.push(boulder, Vector2::new(20.0, 0.0), 1.0, &masses, 1);
+        assert!((moved - Vector2::new(5.0 - CONTACT_SKIN, 0.0)).magnitude() < 1e-3, "{:?}", moved);
+
+        // Pushing it at an angle doesn't slide it along the wall.
+        let moved = space.push(boulder, Vector2::new(10.0, 10.0), 1.0, &masses, 1);
+        assert!(moved.magnitude() < 0.05, "{:?}", moved);
+    }
+
+    #[test]
+    fn chains_as_deep_as_allowed() {
+        // Each one down the line takes half of what it was pushed with, so the whole row ends up going a quarter.
+        let mut space = Space::new();
+        let boxes = row(&mut space, 3);
+        let masses: HashMap<_, _> = boxes.iter().map(|&x| (x, 1.0)).collect();
+        let moved = space.push(boxes[0], Vector2::new(10.0, 0.0), 1.0, &masses, 3);
+        assert!((moved - Vector2::new(1.25, 0.0)).magnitude() < 0.05, "{:?}", moved);
+        for (i, &si) in boxes.iter().enumerate() {
+            let x = space.shape(si).unwrap().x() - i as f32 * (10.0 + CONTACT_SKIN);
+            assert!((x - 1.25).abs() < 0.05, "box {} went {}", i, x);
+        }
+
+        // One too many in a row and the whole line acts like a wall.
+        let mut space = Space::new();
+        let boxes = row(&mut space, 4);
+        let masses: HashMap<_, _> = boxes.iter().map(|&x| (x, 1.0)).collect();
+        let moved = space.push(boxes[0], Vector2::new(10.0, 0.0), 1.0, &masses, 3);
+        assert!(moved.magnitude() < 0.05, "{:?}", moved);
+        assert_eq!(space.shape(boxes[3]).unwrap().x(), 3.0 * (10.0 + CONTACT_SKIN));
+    }
+}
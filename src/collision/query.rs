@@ -0,0 +1,225 @@
+use std::cmp::Ordering;
+use cgmath::{prelude::*, Vector2, Point2, dot};
+use super::{Shape, ShapeType, ShapeIndex, Space};
+
+// How far out (in cells) `nearest` searches the grid before it just checks everything.
+const NEAREST_MAX_CELLS: f32 = 64.0;
+
+pub(super) fn closest_on_segment(p: Point2<f32>, a: Point2<f32>, b: Point2<f32>) -> Point2<f32> {
+    let ab = b - a;
+    let t = if ab.magnitude2() > 0.0 { (dot(p - a, ab) / ab.magnitude2()).clamp(0.0, 1.0) } else { 0.0 };
+    a + ab * t
+}
+
+fn segment_distance(p: Point2<f32>, a: Point2<f32>, b: Point2<f32>) -> f32 {
+    (p - closest_on_segment(p, a, b)).magnitude()
+}
+
+impl Shape {
+    pub fn contains_point(&self, p: Point2<f32>) -> bool {
+        self.distance_to_point(p) <= 0.0
+    }
+
+    // How far the point is from the edge of the shape, or 0 if it's inside.
+    pub fn distance_to_point(&self, p: Point2<f32>) -> f32 {
+        match self.stype {
+            ShapeType::AABB(_) | ShapeType::ConvexPolygon(_) => {
+                use cgmath::EuclideanSpace;
+
+                let points = self.convex_points().unwrap();
+                let centroid = Point2::centroid(&points);
+                let edges: Vec<_> = points.iter().zip(points.iter().cycle().skip(1)).collect();
+                let inside = edges.iter().all(|&(a, b)| {
+                    let edge = b - a;
+                    let mut n = Vector2::new(edge.y, -edge.x);
+                    if dot(n, a - centroid) < 0.0 {
+                        n = -n;
+                    }
+                    dot(n, p - a) <= 0.0
+                });
+
+                if inside {
+                    0.0
+                } else {
+                    edges.iter().map(|&(a, b)| segment_distance(p, *a, *b)).fold(std::f32::MAX, f32::min)
+                }
+            },
+            ShapeType::Circle(ref circle) => {
+                ((p - Point2::new(self.x, self.y)).magnitude() - circle.r).max(0.0)
+            },
+            ShapeType::Compound(ref compound) => {
+                compound.0.iter()
+                    .map(|x| x.shifted(self.x, self.y).distance_to_point(p))
+                    .fold(std::f32::MAX, f32::min)
+            },
+        }
+    }
+}
+
+impl Space {
+    // Everything on a layer in `mask` that covers the point.
+    pub fn shapes_at_point<F>(&self, point: Point2<f32>, mask: u32, mut filter: F) -> Vec<ShapeIndex>
+        where F: FnMut(ShapeIndex, &Shape) -> bool {
+        self.candidates(point, point).into_iter()
+            .filter(|&si| {
+                let shape = self.shapes_array.get(si).unwrap();
+                shape.collidable && shape.layer & mask != 0 && shape.contains_point(point) && filter(si, shape)
+            })
+            .collect()
+    }
+
+    // Everything on a layer in `mask` that overlaps the rectangle.
+    pub fn shapes_in_rect<F>(&self, min: Point2<f32>, max: Point2<f32>, mask: u32, mut filter: F) -> Vec<ShapeIndex>
+        where F: FnMut(ShapeIndex, &Shape) -> bool {
+        let rect = Shape::new_rectangle_xyxy(min.x, min.y, max.x, max.y);
+        self.candidates(min, max).into_iter()
+            .filter(|&si| {
+                let other = self.shapes_array.get(si).unwrap();
+                other.collidable && other.layer & mask != 0 && rect.is_colliding(other).is_some() && filter(si, other)
+            })
+            .collect()
+    }
+
+    // Everything the shape would collide with if it were in the space, going by its layer and mask.
+    // The shape doesn't have to be in the space; if it is, it won't report itself.
+    pub fn shapes_overlapping<F>(&self, shape: &Shape, mut filter: F) -> Vec<ShapeIndex>
+        where F: FnMut(ShapeIndex, &Shape) -> bool {
+        let (min, max) = shape.bounds();
+        self.candidates(min, max).into_iter()
+            .filter(|&si| {
+                let other = self.shapes_array.get(si).unwrap();
+                !std::ptr::eq(shape, other) && shape.interacts_with(other) && shape.is_colliding(other).is_some() && filter(si, other)
+            })
+            .collect()
+    }
+
+    // The closest shape on a layer in `mask` to the point, and how far away it is.
+    pub fn nearest<F>(&self, point: Point2<f32>, mask: u32, mut filter: F) -> Option<(ShapeIndex, f32)>
+        where F: FnMut(ShapeIndex, &Shape) -> bool {
+        let mut closest = |candidates: Vec<ShapeIndex>| {
+            candidates.into_iter()
+                .filter_map(|si| {
+                    let shape = self.shapes_array.get(si).unwrap();
+                    if shape.collidable && shape.layer & mask != 0 && filter(si, shape) {
+                        Some((si, shape.distance_to_point(point)))
+                    } else {
+                        None
+                    }
+                })
+                .min_by(|l, r| l.1.partial_cmp(&r.1).unwrap_or(Ordering::Equal))
+        };
+
+        // Search a growing square around the point. A hit closer than the square's half-width
+        // can't be beaten by anything outside it.
+        let mut reach = self.broadphase.cell_size();
+        while reach <= self.broadphase.cell_size() * NEAREST_MAX_CELLS {
+            let (min, max) = (Point2::new(point.x - reach, point.y - reach), Point2::new(point.x + reach, point.y + reach));
+            if let Some(best) = closest(self.candidates(min, max)) {
+                if best.1 <= reach {
+                    return Some(best)
+                }
+            }
+            reach *= 2.0;
+        }
+
+        closest(self.shapes.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ALL_LAYERS;
+
+    fn tagged(mut shape: Shape, tag: &str) -> Shape {
+        shape.tags_mut().push(tag.to_string());
+        shape
+    }
+
+    #[test]
+    fn shapes_at_point() {
+        let mut space = Space::new();
+        let crate_ = space.add_shape(tagged(Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0), "crate"));
+        let barrel = space.add_shape(tagged(Shape::new_circle(12.0, 5.0, 4.0).with_layers(2, ALL_LAYERS), "barrel"));
+        let ramp = space.add_shape(Shape::new_convex_polygon(0.0, 20.0, vec![Point2::new(0.0, 0.0), Point2::new(10.0, 10.0), Point2::new(0.0, 10.0)]).unwrap());
+
+        let at = |space: &Space, x, y, mask| space.shapes_at_point(Point2::new(x, y), mask, |_, _| true);
+        assert_eq!(at(&space, 9.0, 5.0, ALL_LAYERS), vec![crate_, barrel]);
+        assert_eq!(at(&space, 9.0, 5.0, 1), vec![crate_]);
+        assert_eq!(at(&space, 2.0, 28.0, ALL_LAYERS), vec![ramp]);
+        // Under the ramp's slope, outside the triangle.
+        assert!(at(&space, 8.0, 22.0, ALL_LAYERS).is_empty());
+
+        assert_eq!(space.shapes_at_point(Point2::new(9.0, 5.0), ALL_LAYERS, |_, x| x.has_tags(&["barrel"])), vec![barrel]);
+        assert!(space.shapes_at_point(Point2::new(9.0, 5.0), ALL_LAYERS, |_, x| x.has_tags(&["barrel", "crate"])).is_empty());
+    }
+
+    #[test]
+    fn shapes_overlapping() {
+        let mut space = Space::new();
+        let a = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0));
+        let b = space.add_shape(tagged(Shape::new_rectangle_xywh(5.0, 0.0, 10.0, 10.0), "door"));
+        let c = space.add_shape(Shape::new_circle(8.0, 8.0, 3.0).with_layers(2, 2));
+
+        // A shape that's in the space doesn't report itself.
+        let shape = space.shape(a).unwrap();
+        assert_eq!(space.shapes_overlapping(shape, |_, _| true), vec![b]);
+        // But a copy of it that isn't in the space does, going by the copy's layer and mask.
+        let copy = shape.clone().with_layers(ALL_LAYERS, ALL_LAYERS);
+        assert_eq!(space.shapes_overlapping(&copy, |_, _| true), vec![a, b, c]);
+        assert_eq!(space.shapes_overlapping(&copy, |_, x| x.has_tags(&["door"])), vec![b]);
+        assert!(space.shapes_overlapping(&copy.shifted(100.0, 0.0), |_, _| true).is_empty());
+    }
+
+    #[test]
+    fn nearest() {
+        let mut space = Space::new();
+        let point = Point2::new(0.0, 0.0);
+        assert!(space.nearest(point, ALL_LAYERS, |_, _| true).is_none());
+
+        // Nothing within the first 64 unit square, so it has to grow before it finds either.
+        let far = space.add_shape(Shape::new_circle(300.0, 0.0, 1.0));
+        let near = space.add_shape(tagged(Shape::new_circle(0.0, -151.0, 1.0), "coin"));
+        assert_eq!(space.nearest(point, ALL_LAYERS, |_, _| true), Some((near, 150.0)));
+
+        // Found in the first square, but out in its corner. Something just past the square's edge is
+        // closer, so that can't be the answer yet.
+        let corner = space.add_shape(Shape::new_circle(60.0, 60.0, 1.0));
+        let edge = space.add_shape(Shape::new_circle(-71.0, 0.0, 1.0));
+        let (si, distance) = space.nearest(point, ALL_LAYERS, |_, _| true).unwrap();
+        assert_eq!((si, distance), (edge, 70.0));
+        assert!(space.shape(corner).unwrap().distance_to_point(point) > distance);
+
+        // Right on top of it.
+        let under = space.add_shape(Shape::new_rectangle_xywh(-1.0, -1.0, 2.0, 2.0).with_layers(2, ALL_LAYERS));
+        assert_eq!(space.nearest(point, ALL_LAYERS, |_, _| true), Some((under, 0.0)));
+        assert_eq!(space.nearest(point, 1, |_, _| true).map(|x| x.0), Some(edge));
+
+        // Filtering skips closer ones, and past the search limit it falls back to checking everything.
+        assert_eq!(space.nearest(point, ALL_LAYERS, |_, x| x.has_tags(&["coin"])).map(|x| x.0), Some(near));
+        let very_far = space.add_shape(tagged(Shape::new_circle(100_000.0, 0.0, 1.0), "star"));
+        assert_eq!(space.nearest(point, ALL_LAYERS, |_, x| x.has_tags(&["star"])).map(|x| x.0), Some(very_far));
+        assert_eq!(space.nearest(point, ALL_LAYERS, |si, _| si == far).map(|x| x.0), Some(far));
+    }
+
+    #[test]
+    fn shapes_in_rect_by_layer() {
+        let mut space = Space::new();
+        let a = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 10.0).with_layers(1, 0));
+        let b = space.add_shape(Shape::new_rectangle_xywh(20.0, 0.0, 10.0, 10.0).with_layers(2, ALL_LAYERS));
+        let c = space.add_shape(Shape::new_circle(1000.0, 1000.0, 5.0).with_layers(3, 2));
+        let (min, max) = (Point2::new(-5.0, -5.0), Point2::new(25.0, 5.0));
+
+        // Only the shape's own layer matters. What it's masked to hit is beside the point.
+        assert_eq!(space.shapes_in_rect(min, max, 1, |_, _| true), vec![a]);
+        assert_eq!(space.shapes_in_rect(min, max, 2, |_, _| true), vec![b]);
+        assert_eq!(space.shapes_in_rect(min, max, 2, |si, _| si != b), vec![]);
+
+        // Covering way more cells than there are shapes just checks them all.
+        let huge = (Point2::new(-1e30, -1e30), Point2::new(1e30, 1e30));
+        assert!(space.broadphase.cell_count(huge.0, huge.1) > std::u32::MAX as u64);
+        let mut everything = space.shapes_in_rect(huge.0, huge.1, ALL_LAYERS, |_, _| true);
+        everything.sort_by_key(|x| (x.index(), x.generation()));
+        assert_eq!(everything, vec![a, b, c]);
+    }
+}
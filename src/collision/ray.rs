@@ -0,0 +1,274 @@
+use std::collections::HashSet;
+use std::cmp::Ordering;
+use cgmath::{prelude::*, Vector2, Point2, dot};
+use super::{Shape, ShapeType, ShapeIndex, Space};
+
+#[derive(Clone, Debug)]
+pub struct RayHit {
+    pub shape: ShapeIndex,
+    pub point: Point2<f32>,
+    // Faces back towards the ray. A ray starting inside a shape hits it at distance 0, facing straight back.
+    pub normal: Vector2<f32>,
+    pub distance: f32,
+}
+
+// Clips the ray against every edge's half-plane (Cyrus-Beck). The distance and normal of the
+// last edge the ray enters through is where it hits. A negative distance means the origin is inside.
+pub(super) fn clip_convex(origin: Point2<f32>, dir: Vector2<f32>, max_dist: f32, points: &[Point2<f32>]) -> Option<(f32, Vector2<f32>)> {
+    use cgmath::EuclideanSpace;
+
+    let centroid = Point2::centroid(points);
+    let (mut t_enter, mut t_exit) = (std::f32::NEG_INFINITY, max_dist);
+    let mut enter_normal = -dir;
+
+    for (p, q) in points.iter().zip(points.iter().cycle().skip(1)) {
+        let edge = q - p;
+        let mut n = Vector2::new(edge.y, -edge.x);
+        if dot(n, p - centroid) < 0.0 {
+            n = -n;
+        }
+
+        let denom = dot(n, dir);
+        let num = dot(n, p - origin);
+        if denom == 0.0 {
+            if num < 0.0 {
+                return None
+            }
+        } else if denom < 0.0 {
+            let t = num / denom;
+            if t > t_enter {
+                t_enter = t;
+                enter_normal = n.normalize();
+            }
+        } else {
+            t_exit = t_exit.min(num / denom);
+        }
+
+        if t_enter.max(0.0) > t_exit {
+            return None
+        }
+    }
+
+    Some((t_enter, enter_normal))
+}
+
+// Same deal as clip_convex, but for a circle.
+pub(super) fn clip_circle(origin: Point2<f32>, dir: Vector2<f32>, max_dist: f32, center: Point2<f32>, r: f32) -> Option<(f32, Vector2<f32>)> {
+    let m = origin - center;
+    let b = dot(m, dir);
+    let c = dot(m, m) - r * r;
+    if c > 0.0 && b > 0.0 {
+        return None
+    }
+
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None
+    }
+
+    let t = -b - discriminant.sqrt();
+    if t > max_dist {
+        None
+    } else if t < 0.0 {
+        Some((t, -dir))
+    } else {
+        Some((t, (origin + dir * t - center).normalize()))
+    }
+}
+
+fn from_inside(hit: (f32, Vector2<f32>), dir: Vector2<f32>) -> (f32, Vector2<f32>) {
+    if hit.0 < 0.0 { (0.0, -dir) } else { hit }
+}
+
+impl Shape {
+    // Where a ray (with a normalized direction) first touches this shape, as a distance and normal.
+    fn ray_intersection(&self, origin: Point2<f32>, dir: Vector2<f32>, max_dist: f32) -> Option<(f32, Vector2<f32>)> {
+        if !self.collidable {
+            return None
+        }
+
+        match self.stype {
+            ShapeType::AABB(_) | ShapeType::ConvexPolygon(_) => {
+                clip_convex(origin, dir, max_dist, &self.convex_points().unwrap()).map(|x| from_inside(x, dir))
+            },
+            ShapeType::Circle(ref circle) => {
+                clip_circle(origin, dir, max_dist, Point2::new(self.x, self.y), circle.r).map(|x| from_inside(x, dir))
+            },
+            ShapeType::Compound(ref compound) => {
+                compound.0.iter()
+                    .filter_map(|x| x.shifted(self.x, self.y).ray_intersection(origin, dir, max_dist))
+                    .min_by(|l, r| l.0.partial_cmp(&r.0).unwrap_or(Ordering::Equal))
+            },
+        }
+    }
+}
+
+impl Space {
+    fn ray_hit(&self, si: ShapeIndex, origin: Point2<f32>, dir: Vector2<f32>, max_dist: f32) -> Option<RayHit> {
+        self.shapes_array.get(si)
+            .and_then(|shape| shape.ray_intersection(origin, dir, max_dist))
+            .map(|(distance, normal)| RayHit {
+                shape: si,
+                point: origin + dir * distance,
+                normal,
+                distance,
+            })
+    }
+
+    // The closest shape along the ray, skipping any not on a layer in `mask` and any the filter rejects.
+    // `max_dist` has to be finite; there's no end to an infinite ray with nothing on it.
+    pub fn raycast<F>(&self, origin: Point2<f32>, dir: Vector2<f32>, max_dist: f32, mask: u32, mut filter: F) -> Option<RayHit>
+        where F: FnMut(ShapeIndex, &Shape) -> bool {
+        if dir.magnitude2() == 0.0 || !max_dist.is_finite() || !origin.x.is_finite() || !origin.y.is_finite() {
+            return None
+        }
+        let dir = dir.normalize();
+
+        let mut tested = HashSet::new();
+        let mut best: Option<RayHit> = None;
+        // Cells come back in the order the ray passes through them, so once we have a hit
+        // closer than the next cell there's nothing left that could beat it.
+        for (cell, t_enter) in self.broadphase.ray_cells(origin, dir, max_dist) {
+            if best.as_ref().map(|x| x.distance < t_enter).unwrap_or(false) {
+                break
+            }
+            for &si in self.cell(cell) {
+                let shape = self.shapes_array.get(si).unwrap();
+                if !tested.insert(si) || shape.layer & mask == 0 || !filter(si, shape) {
+                    continue
+                }
+                if let Some(hit) = self.ray_hit(si, origin, dir, max_dist) {
+                    if best.as_ref().map(|x| hit.distance < x.distance).unwrap_or(true) {
+                        best = Some(hit);
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    // Every shape on a layer in `mask` that the segment touches, closest first.
+    pub fn segment_cast<F>(&self, from: Point2<f32>, to: Point2<f32>, mask: u32, mut filter: F) -> Vec<RayHit>
+        where F: FnMut(ShapeIndex, &Shape) -> bool {
+        let max_dist = (to - from).magnitude();
+        if max_dist == 0.0 || !max_dist.is_finite() || !from.x.is_finite() || !from.y.is_finite() {
+            return vec![]
+        }
+        let dir = (to - from) / max_dist;
+
+        let mut tested = HashSet::new();
+        let mut hits = vec![];
+        for (cell, _) in self.broadphase.ray_cells(from, dir, max_dist) {
+            for &si in self.cell(cell) {
+                let shape = self.shapes_array.get(si).unwrap();
+                if !tested.insert(si) || shape.layer & mask == 0 || !filter(si, shape) {
+                    continue
+                }
+                if let Some(hit) = self.ray_hit(si, from, dir, max_dist) {
+                    hits.push(hit);
+                }
+            }
+        }
+        hits.sort_by(|l, r| l.distance.partial_cmp(&r.distance).unwrap_or(Ordering::Equal));
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ALL_LAYERS;
+    use super::super::tests::close;
+
+    // Casts a ray along +x at a space holding only `shape`.
+    fn cast_at(shape: Shape, origin: Point2<f32>) -> Option<RayHit> {
+        let mut space = Space::new();
+        space.add_shape(shape);
+        space.raycast(origin, Vector2::new(1.0, 0.0), 1000.0, ALL_LAYERS, |_, _| true)
+    }
+
+    fn assert_hit(hit: Option<RayHit>, point: Point2<f32>, normal: Vector2<f32>, distance: f32) {
+        let hit = hit.expect("should hit");
+        assert!(close(hit.point.to_vec(), point.to_vec()), "{:?}", hit);
+        assert!(close(hit.normal, normal), "{:?}", hit);
+        assert!((hit.distance - distance).abs() < 1e-4, "{:?}", hit);
+    }
+
+    #[test]
+    fn every_shape_type() {
+        let origin = Point2::new(0.0, 0.0);
+        assert_hit(cast_at(Shape::new_rectangle_xywh(30.0, -10.0, 10.0, 20.0), origin), Point2::new(30.0, 0.0), Vector2::new(-1.0, 0.0), 30.0);
+        assert_hit(cast_at(Shape::new_circle(50.0, 0.0, 10.0), origin), Point2::new(40.0, 0.0), Vector2::new(-1.0, 0.0), 40.0);
+        // Off centre, the circle's normal tilts away from the ray.
+        assert_hit(cast_at(Shape::new_circle(50.0, 6.0, 10.0), origin), Point2::new(42.0, 0.0), Vector2::new(-0.8, -0.6), 42.0);
+
+        // Hits the slanted face of a right triangle.
+        let triangle = Shape::new_convex_polygon(0.0, 0.0, vec![Point2::new(60.0, -20.0), Point2::new(60.0, 20.0), Point2::new(40.0, 20.0)]).unwrap();
+        assert_hit(cast_at(triangle, origin), Point2::new(50.0, 0.0), Vector2::new(-2.0, -1.0).normalize(), 50.0);
+
+        // Two boxes with a gap between: whichever child is first along the ray, even from inside the gap.
+        let boxes = || Shape::new_compound(vec![
+            Shape::new_rectangle_xywh(0.0, -5.0, 10.0, 10.0),
+            Shape::new_rectangle_xywh(20.0, -5.0, 10.0, 10.0),
+        ]);
+        assert_hit(cast_at(boxes(), Point2::new(-20.0, 0.0)), Point2::new(0.0, 0.0), Vector2::new(-1.0, 0.0), 20.0);
+        assert_hit(cast_at(boxes(), Point2::new(15.0, 0.0)), Point2::new(20.0, 0.0), Vector2::new(-1.0, 0.0), 5.0);
+
+        // Starting inside counts as a hit right away, facing back.
+        assert_hit(cast_at(Shape::new_circle(0.0, 0.0, 5.0), origin), origin, Vector2::new(-1.0, 0.0), 0.0);
+        assert!(cast_at(Shape::new_circle(0.0, 50.0, 5.0), origin).is_none());
+    }
+
+    #[test]
+    fn segment_cast_closest_first() {
+        let mut space = Space::new();
+        let far = space.add_shape(Shape::new_rectangle_xywh(100.0, -10.0, 10.0, 20.0));
+        let near = space.add_shape(Shape::new_circle(30.0, 0.0, 5.0));
+        let middle = space.add_shape(Shape::new_convex_polygon(60.0, -5.0, vec![Point2::new(0.0, 0.0), Point2::new(10.0, 5.0), Point2::new(0.0, 10.0)]).unwrap());
+        let (from, to) = (Point2::new(0.0, 0.0), Point2::new(200.0, 0.0));
+
+        let hits = space.segment_cast(from, to, ALL_LAYERS, |_, _| true);
+        assert_eq!(hits.iter().map(|x| x.shape).collect::<Vec<_>>(), vec![near, middle, far]);
+        assert!(hits.windows(2).all(|x| x[0].distance <= x[1].distance));
+
+        // Only as far as the end of the segment.
+        let hits = space.segment_cast(from, Point2::new(80.0, 0.0), ALL_LAYERS, |_, _| true);
+        assert_eq!(hits.iter().map(|x| x.shape).collect::<Vec<_>>(), vec![near, middle]);
+    }
+
+    #[test]
+    fn mask_and_filter() {
+        let mut space = Space::new();
+        let near = space.add_shape(Shape::new_rectangle_xywh(20.0, -10.0, 10.0, 20.0).with_layers(2, ALL_LAYERS));
+        let far = space.add_shape(Shape::new_rectangle_xywh(60.0, -10.0, 10.0, 20.0).with_layers(1, ALL_LAYERS));
+        let (origin, dir) = (Point2::new(0.0, 0.0), Vector2::new(1.0, 0.0));
+
+        assert_eq!(space.raycast(origin, dir, 100.0, ALL_LAYERS, |_, _| true).map(|x| x.shape), Some(near));
+        // The near one isn't on layer 1, so the ray goes on to the far one.
+        assert_eq!(space.raycast(origin, dir, 100.0, 1, |_, _| true).map(|x| x.shape), Some(far));
+        assert_eq!(space.raycast(origin, dir, 100.0, 4, |_, _| true).map(|x| x.shape), None);
+        assert_eq!(space.raycast(origin, dir, 100.0, ALL_LAYERS, |si, _| si != near).map(|x| x.shape), Some(far));
+
+        let to = Point2::new(100.0, 0.0);
+        assert_eq!(space.segment_cast(origin, to, 2, |_, _| true).iter().map(|x| x.shape).collect::<Vec<_>>(), vec![near]);
+        assert!(space.segment_cast(origin, to, ALL_LAYERS, |_, shape| shape.x() > 100.0).is_empty());
+    }
+
+    #[test]
+    fn long_rays() {
+        let mut space = Space::new();
+        let wall = space.add_shape(Shape::new_rectangle_xywh(100.0, -10.0, 10.0, 20.0));
+        let (origin, dir) = (Point2::new(0.0, 0.0), Vector2::new(1.0, 0.0));
+
+        // The grid walk only goes as far as it's asked to, so even an endless ray can be stepped along.
+        assert_eq!(space.broadphase.ray_cells(origin, dir, std::f32::INFINITY).take(5).count(), 5);
+
+        let hit = space.raycast(origin, dir, 1e7, ALL_LAYERS, |_, _| true).unwrap();
+        assert_eq!(hit.shape, wall);
+        assert!((hit.distance - 100.0).abs() < 1e-3);
+
+        assert!(space.raycast(origin, dir, std::f32::INFINITY, ALL_LAYERS, |_, _| true).is_none());
+        assert!(space.raycast(origin, dir, std::f32::NAN, ALL_LAYERS, |_, _| true).is_none());
+        assert!(space.segment_cast(origin, Point2::new(std::f32::INFINITY, 0.0), ALL_LAYERS, |_, _| true).is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+use cgmath::{prelude::*, Vector2, dot};
+
+// Below this fraction of the original speed, what's left after projecting is just rounding error
+// from hitting the surface head on, and stretching it back out would send us off in a random direction.
+const SLIDE_EPSILON: f32 = 1e-3;
+
+// What happens to a body's velocity after it runs into something.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResponseType {
+    // Drop the part of the velocity going into the surface. Hitting a wall at an angle slows you down.
+    #[default]
+    Project,
+    // Same as Project, but keep the original speed, just turned along the surface.
+    Slide,
+    // Reflect off the surface. 1.0 keeps all the speed, 0.0 is the same as Project.
+    Bounce(f32),
+}
+
+impl ResponseType {
+    // The velocity after hitting every surface in `normals` (each facing back at the body, any length).
+    // Surfaces we're already moving away from are left alone. Wedged into a corner, Project and Slide
+    // stop dead rather than squeeze out along one wall and into the other.
+    pub fn apply(&self, velocity: Vector2<f32>, normals: &[Vector2<f32>]) -> Vector2<f32> {
+        let normals: Vec<_> = normals.iter().filter(|x| x.magnitude2() > 0.0).map(|x| x.normalize()).collect();
+
+        match *self {
+            ResponseType::Project | ResponseType::Slide => {
+                // Clearing one surface can push us into another, so go round until nothing's in the way.
+                // If that hasn't settled after a pass per surface, we're in a corner.
+                let mut v = velocity;
+                for _ in 0..normals.len() {
+                    let mut clear = true;
+                    for &n in normals.iter() {
+                        let d = dot(v, n);
+                        if d < 0.0 {
+                            v -= n * d;
+                            clear = false;
+                        }
+                    }
+                    if clear {
+                        break
+                    }
+                }
+                if normals.iter().any(|&n| dot(v, n) < -SLIDE_EPSILON * velocity.magnitude()) {
+                    return Vector2::new(0.0, 0.0)
+                }
+
+                if *self == ResponseType::Slide {
+                    if v.magnitude() > SLIDE_EPSILON * velocity.magnitude() {
+                        v.normalize() * velocity.magnitude()
+                    } else {
+                        Vector2::new(0.0, 0.0)
+                    }
+                } else {
+                    v
+                }
+            },
+            ResponseType::Bounce(restitution) => {
+                let mut v = velocity;
+                for &n in normals.iter() {
+                    let d = dot(v, n);
+                    if d < 0.0 {
+                        v -= n * d * (1.0 + restitution);
+                    }
+                }
+                v
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::tests::close;
+
+    // Floor, ceiling, both walls, and a diagonal.
+    fn walls() -> Vec<Vector2<f32>> {
+        vec![
+            Vector2::new(0.0, -1.0),
+            Vector2::new(0.0, 1.0),
+            Vector2::new(1.0, 0.0),
+            Vector2::new(-1.0, 0.0),
+            Vector2::new(1.0, 1.0).normalize(),
+        ]
+    }
+
+    #[test]
+    fn project_drops_the_normal_part() {
+        for n in walls() {
+            let along = Vector2::new(-n.y, n.x);
+            let v = (along - n) * 3.0;
+            let after = ResponseType::Project.apply(v, &[n]);
+            assert!(close(after, along * 3.0), "{:?} against {:?} gave {:?}", v, n, after);
+        }
+    }
+
+    #[test]
+    fn slide_keeps_speed() {
+        for n in walls() {
+            let along = Vector2::new(-n.y, n.x);
+            let v = (along - n) * 3.0;
+            let after = ResponseType::Slide.apply(v, &[n]);
+            assert!(close(after, along.normalize() * v.magnitude()), "{:?} against {:?} gave {:?}", v, n, after);
+        }
+    }
+
+    #[test]
+    fn slide_stops_head_on() {
+        for n in walls() {
+            assert!(close(ResponseType::Slide.apply(-n * 5.0, &[n]), Vector2::new(0.0, 0.0)));
+        }
+    }
+
+    #[test]
+    fn bounce_reflects() {
+        for n in walls() {
+            let along = Vector2::new(-n.y, n.x);
+            let v = along - n;
+            assert!(close(ResponseType::Bounce(1.0).apply(v, &[n]), along + n));
+            assert!(close(ResponseType::Bounce(0.5).apply(v, &[n]), along + n * 0.5));
+            assert!(close(ResponseType::Bounce(0.0).apply(v, &[n]), ResponseType::Project.apply(v, &[n])));
+        }
+    }
+
+    #[test]
+    fn moving_away_is_untouched() {
+        let v = Vector2::new(2.0, -3.0);
+        for response in [ResponseType::Project, ResponseType::Slide, ResponseType::Bounce(0.8)].iter() {
+            assert!(close(response.apply(v, &[Vector2::new(0.0, -1.0)]), v));
+            assert!(close(response.apply(v, &[]), v));
+        }
+    }
+
+    #[test]
+    fn square_corner() {
+        // Bottom-left corner of a room: a wall to the left and the floor below.
+        let corner = [Vector2::new(1.0, 0.0), Vector2::new(0.0, -1.0)];
+        let v = Vector2::new(-2.0, 1.0);
+        assert!(close(ResponseType::Project.apply(v, &corner), Vector2::new(0.0, 0.0)));
+        assert!(close(ResponseType::Slide.apply(v, &corner), Vector2::new(0.0, 0.0)));
+        assert!(close(ResponseType::Bounce(1.0).apply(v, &corner), Vector2::new(2.0, -1.0)));
+
+        // Only moving into one of them slides along the other.
+        assert!(close(ResponseType::Slide.apply(Vector2::new(-2.0, -1.0), &corner), Vector2::new(0.0, -(5.0f32).sqrt())));
+    }
+
+    #[test]
+    fn acute_corner() {
+        // A narrow wedge opening to the right. Projecting off either side drives us into the other.
+        let wedge = [Vector2::new(1.0, 3.0).normalize(), Vector2::new(1.0, -3.0).normalize()];
+        assert!(close(ResponseType::Project.apply(Vector2::new(-4.0, 0.5), &wedge), Vector2::new(0.0, 0.0)));
+        assert!(close(ResponseType::Slide.apply(Vector2::new(-4.0, 0.5), &wedge), Vector2::new(0.0, 0.0)));
+    }
+
+    #[test]
+    fn obtuse_corner() {
+        // Floor meeting a ramp that rises to the left. Pushing down and right clears the floor,
+        // which already has us heading away from the ramp.
+        let corner = [Vector2::new(0.0, -1.0), Vector2::new(1.0, -3.0).normalize()];
+        let v = Vector2::new(1.0, 2.0);
+        assert!(close(ResponseType::Project.apply(v, &corner), Vector2::new(1.0, 0.0)));
+        assert!(close(ResponseType::Slide.apply(v, &corner), Vector2::new(v.magnitude(), 0.0)));
+    }
+}
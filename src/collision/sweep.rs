@@ -0,0 +1,466 @@
+use std::cmp::Ordering;
+use cgmath::{prelude::*, Vector2, Point2, dot};
+use super::{Shape, ShapeType, ShapeIndex, Space, CONTACT_SKIN};
+use super::ray::{clip_convex, clip_circle};
+use super::query::closest_on_segment;
+
+// How far past the corner a nudge takes us, so the next sweep doesn't graze it.
+const NUDGE_SKIN: f32 = 0.01;
+// How close to straight along an axis a surface's normal has to be to nudge around it.
+const NUDGE_MIN_ALIGNMENT: f32 = 0.99;
+
+#[derive(Clone, Debug)]
+pub struct SweepHit {
+    pub shape: ShapeIndex,
+    // How far through the motion the contact happens, from 0 to 1.
+    pub time: f32,
+    // Surface normal of whatever we ran into, facing back at the moving shape.
+    pub normal: Vector2<f32>,
+}
+
+// Andrew's monotone chain. Collinear points are dropped.
+fn convex_hull(mut points: Vec<Point2<f32>>) -> Vec<Point2<f32>> {
+    points.sort_by(|l, r| (l.x, l.y).partial_cmp(&(r.x, r.y)).unwrap_or(Ordering::Equal));
+    points.dedup();
+    if points.len() < 3 {
+        return points
+    }
+
+    let cross = |o: Point2<f32>, a: Point2<f32>, b: Point2<f32>| (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x);
+    let mut hull: Vec<Point2<f32>> = vec![];
+    for pass in 0..2 {
+        let start = hull.len();
+        for &p in points.iter() {
+            while hull.len() >= start + 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0.0 {
+                hull.pop();
+            }
+            hull.push(p);
+        }
+        hull.pop();
+        if pass == 0 {
+            points.reverse();
+        }
+    }
+    hull
+}
+
+// How far the point is outside a convex point set (negative once it's inside), and which way is out.
+fn signed_distance(p: Point2<f32>, points: &[Point2<f32>]) -> (f32, Vector2<f32>) {
+    use cgmath::EuclideanSpace;
+
+    if points.len() >= 3 {
+        let centroid = Point2::centroid(points);
+        let nearest_edge = points.iter().zip(points.iter().cycle().skip(1))
+            .map(|(a, b)| {
+                let edge = b - a;
+                let mut n = Vector2::new(edge.y, -edge.x).normalize();
+                if dot(n, a - centroid) < 0.0 {
+                    n = -n;
+                }
+                (dot(n, p - a), n)
+            })
+            .max_by(|l, r| l.0.partial_cmp(&r.0).unwrap_or(Ordering::Equal));
+        if let Some((d, n)) = nearest_edge {
+            if d <= 0.0 {
+                return (d, n)
+            }
+        }
+    }
+
+    // Outside, or there's only a point or a segment, so it's the distance to the closest bit of the outline.
+    let closest = points.iter().zip(points.iter().cycle().skip(1))
+        .map(|(a, b)| closest_on_segment(p, *a, *b))
+        .min_by(|l, r| (p - l).magnitude2().partial_cmp(&(p - r).magnitude2()).unwrap_or(Ordering::Equal))
+        .unwrap_or(p);
+    let out = p - closest;
+    let d = out.magnitude();
+    (d, if d > 0.0 { out / d } else { Vector2::new(0.0, 0.0) })
+}
+
+// Where a ray enters a convex point set grown outwards by `r`: the core polygon, a slab along each edge,
+// and a circle on each corner. Entering the union means entering whichever piece comes first.
+// An origin no more than CONTACT_SKIN inside is a body left touching the surface (rounding can easily put
+// it a hair in), so that's a hit straight away if we're heading further in, and nothing if we're not.
+// Any deeper is an overlap sweeping can't do anything about, so None.
+fn clip_rounded_convex(origin: Point2<f32>, dir: Vector2<f32>, max_dist: f32, points: &[Point2<f32>], r: f32) -> Option<(f32, Vector2<f32>)> {
+    let (distance, out) = signed_distance(origin, points);
+    if distance <= r {
+        return if r - distance <= CONTACT_SKIN && dot(dir, out) < 0.0 { Some((0.0, out)) } else { None }
+    }
+
+    let mut hits = vec![];
+    if points.len() >= 3 {
+        hits.push(clip_convex(origin, dir, max_dist, points));
+    }
+    if r > 0.0 {
+        if points.len() >= 2 {
+            for (p, q) in points.iter().zip(points.iter().cycle().skip(1)) {
+                let edge = q - p;
+                if edge.magnitude2() > 0.0 {
+                    let n = Vector2::new(-edge.y, edge.x).normalize() * r;
+                    hits.push(clip_convex(origin, dir, max_dist, &[p - n, q - n, q + n, p + n]));
+                }
+            }
+        }
+        for &p in points.iter() {
+            hits.push(clip_circle(origin, dir, max_dist, p, r));
+        }
+    }
+
+    // We're outside, so anything behind the origin is just rounding.
+    hits.into_iter().flatten()
+        .map(|(t, n)| (t.max(0.0), n))
+        .min_by(|l, r| l.0.partial_cmp(&r.0).unwrap_or(Ordering::Equal))
+}
+
+impl Shape {
+    // Every convex piece of the shape in world space, as a set of points rounded off by a radius.
+    // Boxes and polygons have no rounding, circles are a single point with all rounding.
+    fn convex_parts(&self) -> Vec<(Vec<Point2<f32>>, f32)> {
+        match self.stype {
+            ShapeType::AABB(_) | ShapeType::ConvexPolygon(_) => vec![(self.convex_points().unwrap(), 0.0)],
+            ShapeType::Circle(ref circle) => vec![(vec![Point2::new(self.x, self.y)], circle.r)],
+            ShapeType::Compound(ref compound) => {
+                compound.0.iter().flat_map(|x| x.shifted(self.x, self.y).convex_parts()).collect()
+            },
+        }
+    }
+
+    // Time of impact when this shape moves by `motion` towards `o`, as (time, normal).
+    // Moving shape A hits B exactly when A's position enters B grown by A flipped around (their
+    // Minkowski difference), so every pair of convex pieces boils down to one ray cast.
+    fn sweep_against(&self, motion: Vector2<f32>, o: &Shape) -> Option<(f32, Vector2<f32>)> {
+        if !self.collidable || !o.collidable {
+            return None
+        }
+
+        let len = motion.magnitude();
+        let dir = motion / len;
+        let origin = Point2::new(self.x, self.y);
+
+        let mut best: Option<(f32, Vector2<f32>)> = None;
+        for (a_points, a_r) in self.convex_parts() {
+            for (b_points, b_r) in o.convex_parts() {
+                let mut difference = vec![];
+                for b in b_points.iter() {
+                    for a in a_points.iter() {
+                        difference.push(b - (a - origin));
+                    }
+                }
+                let hull = convex_hull(difference);
+                if let Some((t, n)) = clip_rounded_convex(origin, dir, len, &hull, a_r + b_r) {
+                    if best.map(|x| t < x.0).unwrap_or(true) {
+                        best = Some((t, n));
+                    }
+                }
+            }
+        }
+
+        best.map(|(t, n)| (t / len, n))
+    }
+
+    // How far this shape is from `o` (negative if they overlap), and which way is away from `o`.
+    // Same trick as sweep_against: it's how far our position is outside their Minkowski difference.
+    fn separation(&self, o: &Shape) -> Option<(f32, Vector2<f32>)> {
+        let origin = Point2::new(self.x, self.y);
+
+        let mut best: Option<(f32, Vector2<f32>)> = None;
+        for (a_points, a_r) in self.convex_parts() {
+            for (b_points, b_r) in o.convex_parts() {
+                let mut difference = vec![];
+                for b in b_points.iter() {
+                    for a in a_points.iter() {
+                        difference.push(b - (a - origin));
+                    }
+                }
+                let (d, n) = signed_distance(origin, &convex_hull(difference));
+                let d = d - (a_r + b_r);
+                if best.map(|x| d < x.0).unwrap_or(true) {
+                    best = Some((d, n));
+                }
+            }
+        }
+        best
+    }
+}
+
+impl Space {
+    // Every other shape the moving one could touch on its way that isn't a sensor, and that it interacts with.
+    fn sweep_candidates<'a>(&'a self, shape_index: ShapeIndex, motion: Vector2<f32>) -> impl Iterator<Item=(ShapeIndex, &'a Shape)> + 'a {
+        let shape = self.shapes_array.get(shape_index).unwrap();
+        let (min, max) = shape.bounds();
+        let (min, max) = (
+            Point2::new(min.x + motion.x.min(0.0), min.y + motion.y.min(0.0)),
+            Point2::new(max.x + motion.x.max(0.0), max.y + motion.y.max(0.0)),
+        );
+
+        self.candidates(min, max).into_iter()
+            .filter(move |&x| x != shape_index)
+            .map(move |x| (x, self.shapes_array.get(x).unwrap()))
+            .filter(move |&(_, other)| shape.interacts_with(other) && !shape.sensor() && !other.sensor())
+    }
+
+    // The first shape this one would run into if it moved by `motion`. Sensors never block, nor do one-way
+    // shapes we enter through the right face. Shapes it's only touching (within CONTACT_SKIN) block at
+    // time 0 if we're moving into them. Ones it overlaps any deeper are ignored here; check_collisions is
+    // what gets it back out of those. The exception is a one-way shape we're partway through, which
+    // check_collisions leaves alone: turning back against its direction is blocked straight away.
+    pub fn sweep(&self, shape_index: ShapeIndex, motion: Vector2<f32>) -> Option<SweepHit> {
+        if !self.shapes_allocator.is_live(shape_index) || motion.magnitude2() == 0.0 {
+            return None
+        }
+
+        let shape = self.shapes_array.get(shape_index).unwrap();
+        let mut best: Option<SweepHit> = None;
+        for (si, other) in self.sweep_candidates(shape_index, motion) {
+            let hit = match shape.sweep_against(motion, other) {
+                Some((time, normal)) => Some((time, normal)).filter(|_| other.blocks(normal)),
+                None => other.one_way()
+                    .filter(|&direction| dot(motion, direction) < 0.0)
+                    .filter(|_| shape.separation(other).map(|x| x.0 < 0.0).unwrap_or(false))
+                    .map(|direction| (0.0, direction.normalize())),
+            };
+            if let Some((time, normal)) = hit {
+                if best.as_ref().map(|x| time < x.time).unwrap_or(true) {
+                    best = Some(SweepHit {
+                        shape: si,
+                        time,
+                        normal,
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    // Every solid shape this one interacts with that's no more than `skin` away, with the normal facing
+    // back at this one. One-way shapes only count from the side they block.
+    pub fn touching(&self, shape_index: ShapeIndex, skin: f32) -> Vec<(ShapeIndex, Vector2<f32>)> {
+        let shape = match self.shapes_array.get(shape_index) {
+            Some(shape) if self.shapes_allocator.is_live(shape_index) && shape.collidable && !shape.sensor() => shape,
+            _ => return vec![],
+        };
+        let (min, max) = shape.bounds();
+        let grow = Vector2::new(skin, skin);
+
+        self.candidates(min - grow, max + grow).into_iter()
+            .filter(|&x| x != shape_index)
+            .map(|x| (x, self.shapes_array.get(x).unwrap()))
+            .filter(|&(_, other)| other.collidable && !other.sensor() && shape.interacts_with(other))
+            .filter_map(|(si, other)| shape.separation(other)
+                .filter(|&(d, n)| d <= skin && other.blocks(n))
+                .map(|(_, n)| (si, n)))
+            .collect()
+    }
+
+    // The one-way shapes this one would start going through if it moved by `motion`, earliest first,
+    // along with how far through the motion it reaches each. Only entering through the face that lets
+    // things in counts. Good for kicking off a hop over a ledge.
+    pub fn one_way_crossings(&self, shape_index: ShapeIndex, motion: Vector2<f32>) -> Vec<(ShapeIndex, f32)> {
+        if !self.shapes_allocator.is_live(shape_index) || motion.magnitude2() == 0.0 {
+            return vec![]
+        }
+
+        let shape = self.shapes_array.get(shape_index).unwrap();
+        let mut crossings: Vec<_> = self.sweep_candidates(shape_index, motion)
+            .filter(|x| x.1.one_way().is_some())
+            .filter_map(|(si, other)| shape.sweep_against(motion, other)
+                .filter(|&(_, normal)| !other.blocks(normal))
+                .map(|(time, _)| (si, time)))
+            .collect();
+        crossings.sort_by(|l, r| l.1.partial_cmp(&r.1).unwrap_or(Ordering::Equal));
+        crossings
+    }
+
+    // When a sweep from `motion` got blocked by the very edge of something, the sideways shift that
+    // would take us around it instead, as long as that's no more than `threshold`. Walking through a
+    // doorway a pixel off shouldn't stop you dead. Only works against boxes, since their bounds are the
+    // surface we'd be slipping past; a circle or a slope just looks flat at its very edge. Also only when
+    // we're heading into the surface more than along it, and only if nothing's in the way of the shift.
+    pub fn corner_nudge(&self, shape_index: ShapeIndex, motion: Vector2<f32>, hit: &SweepHit, threshold: f32) -> Option<Vector2<f32>> {
+        let (shape, other) = (self.shapes_array.get(shape_index)?, self.shapes_array.get(hit.shape)?);
+        if !matches!(other.stype, ShapeType::AABB(_)) {
+            return None
+        }
+
+        let side = if hit.normal.x.abs() > hit.normal.y.abs() { Vector2::new(0.0, 1.0) } else { Vector2::new(1.0, 0.0) };
+        if dot(hit.normal, side).abs() > (1.0 - NUDGE_MIN_ALIGNMENT * NUDGE_MIN_ALIGNMENT).sqrt() ||
+            dot(motion, side).abs() >= dot(motion, hit.normal).abs() {
+            return None
+        }
+
+        // How far we'd have to go either way along the surface to get clear of it.
+        let along = |p: Point2<f32>| dot(p.to_vec(), side);
+        let ((min, max), (o_min, o_max)) = (shape.bounds(), other.bounds());
+        let (past_low, past_high) = (along(max) - along(o_min), along(o_max) - along(min));
+
+        let (amount, nudge) = if past_low < past_high {
+            (past_low, -side * (past_low + NUDGE_SKIN))
+        } else {
+            (past_high, side * (past_high + NUDGE_SKIN))
+        };
+        if amount > 0.0 && amount <= threshold && self.sweep(shape_index, nudge).is_none() {
+            Some(nudge)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::BodyKind;
+    use super::super::tests::{Lcg, close};
+
+    #[test]
+    fn touching_then_fast_move() {
+        // Resolving out of a wall leaves the box flush with it, give or take rounding, which is worse out
+        // at big coordinates. Sweeping hard into the wall from there has to hit it, not tunnel through.
+        let mut rng = Lcg(3);
+        for _ in 0..500 {
+            let mut space = Space::new();
+            let (x, y) = (rng.next(4096.0), rng.next(4096.0));
+            let wall = space.add_shape(Shape::new_rectangle_xywh(x, y, 4.0, 64.0));
+            let body = space.add_shape(Shape::new_rectangle_xywh(x - 32.0 + rng.next(2.0), y + rng.next(30.0), 32.0, 32.0));
+            for c in space.check_collisions(body) {
+                space.shape_mut(body).unwrap().shift(c.resolve_x, c.resolve_y);
+            }
+
+            let hit = space.sweep(body, Vector2::new(40.0, 0.0)).expect("should hit the wall");
+            assert_eq!(hit.shape, wall);
+            assert!(hit.time < 1e-3 && close(hit.normal, Vector2::new(-1.0, 0.0)), "{:?}", hit);
+
+            // Touching doesn't hold it back going the other way, or sliding along.
+            assert!(space.sweep(body, Vector2::new(-40.0, 0.0)).is_none());
+            assert!(space.sweep(body, Vector2::new(0.0, 10.0)).is_none());
+        }
+
+        // Properly stuck inside is still left to check_collisions.
+        let mut space = Space::new();
+        space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 4.0, 64.0));
+        let body = space.add_shape(Shape::new_rectangle_xywh(-30.0, 0.0, 32.0, 32.0));
+        assert!(space.sweep(body, Vector2::new(40.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn one_way_ledge() {
+        // A ledge you can drop down off of, but not climb back up or walk into from the side.
+        let mut space = Space::new();
+        let ledge = space.add_shape(Shape::new_rectangle_xywh(0.0, 40.0, 64.0, 4.0).with_one_way(Vector2::new(0.0, 1.0)).with_body(BodyKind::Static));
+
+        let body = space.add_shape(Shape::new_rectangle_xywh(20.0, 10.0, 16.0, 16.0));
+        let down = Vector2::new(0.0, 40.0);
+        assert!(space.sweep(body, down).is_none());
+        assert_eq!(space.one_way_crossings(body, down).iter().map(|x| x.0).collect::<Vec<_>>(), vec![ledge]);
+
+        space.shape_mut(body).unwrap().shift(0.0, 40.0);
+        let up = Vector2::new(0.0, -40.0);
+        assert_eq!(space.sweep(body, up).map(|x| x.shape), Some(ledge));
+        assert!(space.one_way_crossings(body, up).is_empty());
+
+        // Level with it, off to the left.
+        space.shape_mut(body).unwrap().shift(-40.0, -16.0);
+        let sideways = Vector2::new(30.0, 0.0);
+        // Heading a little down doesn't make the end any less of a wall: what counts is the face we go in by.
+        for &motion in [Vector2::new(30.0, 0.0), Vector2::new(30.0, 0.001), Vector2::new(30.0, 5.0), Vector2::new(30.0, -5.0)].iter() {
+            let hit = space.sweep(body, motion).expect("should be blocked from the side");
+            assert_eq!(hit.shape, ledge);
+            assert!(close(hit.normal, Vector2::new(-1.0, 0.0)), "{:?} gave {:?}", motion, hit);
+            assert!(space.one_way_crossings(body, motion).is_empty());
+        }
+
+        // Dropping onto it at an angle still goes in through the top.
+        space.shape_mut(body).unwrap().shift(40.0, -24.0);
+        let diagonal = Vector2::new(10.0, 40.0);
+        assert!(space.sweep(body, diagonal).is_none());
+        assert_eq!(space.one_way_crossings(body, diagonal).len(), 1);
+    }
+
+    #[test]
+    fn reverse_partway_through_a_ledge() {
+        // 2 units into the top of the ledge, partway through hopping down it.
+        let mut space = Space::new();
+        let ledge = space.add_shape(Shape::new_rectangle_xywh(0.0, 40.0, 64.0, 4.0).with_one_way(Vector2::new(0.0, 1.0)).with_body(BodyKind::Static));
+        let body = space.add_shape(Shape::new_rectangle_xywh(20.0, 26.0, 16.0, 16.0));
+
+        // Turning back is blocked straight away, not just ignored as an overlap.
+        for &motion in [Vector2::new(0.0, -10.0), Vector2::new(20.0, -1.0)].iter() {
+            let hit = space.sweep(body, motion).expect("shouldn't get back out the top");
+            assert_eq!((hit.shape, hit.time), (ledge, 0.0));
+            assert!(close(hit.normal, Vector2::new(0.0, 1.0)));
+        }
+
+        // Carrying on down, or along it, is fine.
+        assert!(space.sweep(body, Vector2::new(0.0, 10.0)).is_none());
+        assert!(space.sweep(body, Vector2::new(10.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn corner_nudge() {
+        // Heading right into a wall that only catches the bottom 4 units of the box.
+        let mut space = Space::new();
+        let wall = space.add_shape(Shape::new_rectangle_xywh(40.0, 28.0, 10.0, 72.0).with_body(BodyKind::Static));
+        let body = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 32.0, 32.0));
+        let motion = Vector2::new(40.0, 0.0);
+        let hit = space.sweep(body, motion).unwrap();
+        assert_eq!(hit.shape, wall);
+
+        // Within the threshold, it slips up and over the corner, and from there the way is clear.
+        let nudge = space.corner_nudge(body, motion, &hit, 6.0).expect("should nudge");
+        assert!(close(nudge, Vector2::new(0.0, -4.01)), "{:?}", nudge);
+        space.shape_mut(body).unwrap().shift(nudge.x, nudge.y);
+        assert!(space.sweep(body, motion).is_none());
+        space.shape_mut(body).unwrap().shift(-nudge.x, -nudge.y);
+
+        // Past it, it's just a wall.
+        assert!(space.corner_nudge(body, motion, &hit, 3.0).is_none());
+
+        // So is a corner with no room to slip round, like a ceiling right above.
+        space.add_shape(Shape::new_rectangle_xywh(-10.0, -12.0, 50.0, 10.0).with_body(BodyKind::Static));
+        assert!(space.corner_nudge(body, motion, &hit, 6.0).is_none());
+
+        // Catching the very top of a round post or the tip of a wedge is no corner to slip round,
+        // even though the surface faces nearly straight back there.
+        let rounded = |other: Shape| {
+            let mut space = Space::new();
+            let other = space.add_shape(other.with_body(BodyKind::Static));
+            let body = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 32.0, 32.0));
+            let hit = space.sweep(body, motion).unwrap();
+            assert_eq!(hit.shape, other);
+            assert!(hit.normal.x < -0.99, "{:?}", hit);
+            space.corner_nudge(body, motion, &hit, 6.0)
+        };
+        // Both only reach 6 into the path, so going by their bounds would nudge round them.
+        assert!(rounded(Shape::new_circle(60.0, 32.5, 6.5)).is_none());
+        let wedge = vec![Point2::new(50.0, 29.0), Point2::new(80.0, 26.0), Point2::new(80.0, 60.0)];
+        assert!(rounded(Shape::new_convex_polygon(0.0, 0.0, wedge).unwrap()).is_none());
+    }
+
+    #[test]
+    fn touching_within_skin() {
+        let mut space = Space::new();
+        let wall = space.add_shape(Shape::new_rectangle_xywh(0.0, 0.0, 10.0, 64.0).with_body(BodyKind::Static));
+        let ledge = space.add_shape(Shape::new_rectangle_xywh(-40.0, 40.0, 40.0, 4.0).with_one_way(Vector2::new(0.0, 1.0)));
+        let sensor = space.add_shape(Shape::new_rectangle_xywh(-40.0, 0.0, 40.0, 40.0));
+        *space.shape_mut(sensor).unwrap().sensor_mut() = true;
+        let body = space.add_shape(Shape::new_rectangle_xywh(-20.005, 10.0, 20.0, 20.0));
+
+        // Resting just off the wall counts, the sensor it's sitting in doesn't.
+        let touching = space.touching(body, CONTACT_SKIN);
+        assert_eq!(touching.len(), 1);
+        assert_eq!(touching[0].0, wall);
+        assert!(close(touching[0].1, Vector2::new(-1.0, 0.0)));
+        assert!(space.touching(sensor, CONTACT_SKIN).is_empty());
+
+        // Backing off out of range ends it.
+        space.shape_mut(body).unwrap().shift(-1.0, 0.0);
+        assert!(space.touching(body, CONTACT_SKIN).is_empty());
+
+        // A ledge only touches from the side it blocks: resting below it, not on top of it.
+        space.shape_mut(body).unwrap().shift(0.0, 34.005);
+        assert_eq!(space.touching(body, CONTACT_SKIN).iter().map(|x| x.0).collect::<Vec<_>>(), vec![ledge]);
+        space.shape_mut(body).unwrap().shift(0.0, -24.01);
+        assert!(space.touching(body, CONTACT_SKIN).is_empty());
+    }
+}
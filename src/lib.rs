@@ -0,0 +1,6 @@
+//! The parts of the game that don't need a window: collision, scripting and their support code.
+//! Tools, tests and other binaries can depend on these without pulling in raylib.
+
+pub mod generation;
+pub mod collision;
+pub mod script;
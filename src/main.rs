@@ -1,40 +1,135 @@
 mod timer;
 mod gamepad;
-mod generation;
-mod collision;
-mod script;
 
 use raylib::prelude::*;
 use cgmath::prelude::*;
-use cgmath::{Vector2, dot};
+use cgmath::{Vector2, Point2, dot};
 use std::time::Duration;
 use crate::timer::Timer;
 use crate::gamepad::VirtualGamepadState;
 use specs::{prelude::*, Component, World, Builder};
 use specs::{Read, Write, WriteStorage, ReadStorage, System, Entities};
+use specs::shrev::EventChannel;
 use std::ops::Deref;
-use crate::collision::{Space, ShapeIndex, Shape};
-use std::collections::HashMap;
+use return_rpg::collision::{Space, ShapeIndex, Shape, BodyKind, ResponseType, CollisionLayers, ALL_LAYERS, CONTACT_SKIN};
+use std::collections::{HashMap, BTreeMap};
 use std::path::Path;
+use return_rpg::script::prelude::{SignalChannel, ScriptRng};
+use std::time::SystemTime;
 
 #[derive(Debug, Clone, Copy, Default)]
 struct DeltaTime(Duration);
 
+// How many surfaces a single move can slide along before we give up on the rest of it.
+const MAX_SLIDES: usize = 4;
+// What anything without a Pushable weighs when it pushes something.
+const DEFAULT_MASS: f32 = 1.0;
+// How many pushable bodies in a row one push can shove along. Past that, the line acts like a wall.
+const MAX_PUSH_DEPTH: usize = 3;
+
 #[derive(Debug, Default)]
 struct PhysicsSpace(Space);
 
+// Published by PhysicsSystem on the first frame two entities touch or overlap. `normal` points from `b` towards `a`.
+// Solid bodies count as touching for as long as a dynamic one ends the frame resting up against the other;
+// sensors for as long as they overlap.
+#[derive(Debug, Clone)]
+struct ContactStarted {
+    a: Entity,
+    b: Entity,
+    normal: Vector2<f32>,
+}
+
+// Published on the first frame they stop touching.
+#[derive(Debug, Clone)]
+struct ContactEnded {
+    a: Entity,
+    b: Entity,
+}
+
+// Published by PhysicsSystem when `entity` starts going over a Ledge, so gameplay can play the hop.
+#[derive(Debug, Clone)]
+struct LedgeHop {
+    entity: Entity,
+    ledge: Entity,
+    direction: Vector2<f32>,
+}
+
+type Contacts = BTreeMap<(Entity, Entity), Vector2<f32>>;
+
 struct PhysicsSystem {
     shape_index_mapping: HashMap<ShapeIndex, Entity>,
+    // Everything that touched last frame, so we can tell which contacts are new and which went away.
+    contacts: Contacts,
 }
 
 impl PhysicsSystem {
     pub fn new() -> PhysicsSystem {
         PhysicsSystem {
             shape_index_mapping: HashMap::new(),
+            contacts: BTreeMap::new(),
+        }
+    }
+
+    // Allocates the collider's shape the first time we see it. After that, the shape is where the entity
+    // really is, so copy its position back out (unless it's static and can't have moved).
+    fn sync_collider<C: Collider>(&mut self, space: &mut Space, ent: Entity, pos: &mut Position, collider: &mut C, sensor: bool, one_way: Option<Vector2<f32>>) {
+        let (layer, mask, body) = collider.flags();
+        let si = match *collider.shape_index_mut() {
+            None => {
+                let si = space.add_shape(collider.shape_at(pos.position).with_layers(layer, mask).with_body(body));
+                *collider.shape_index_mut() = Some(si);
+                self.shape_index_mapping.insert(si, ent);
+                si
+            },
+            Some(si) => {
+                if body != BodyKind::Static {
+                    if let Some(shape) = space.shape(si) {
+                        pos.position.x = shape.x();
+                        pos.position.y = shape.y();
+                    }
+                }
+                si
+            },
+        };
+        sync_shape(space, si, layer, mask, body, sensor, one_way);
+    }
+
+    // Pairs are keyed smaller entity first so each one is only tracked once.
+    fn record_contact(contacts: &mut Contacts, a: Entity, b: Entity, normal: Vector2<f32>) {
+        if a < b {
+            contacts.insert((a, b), normal);
+        } else {
+            contacts.insert((b, a), -normal);
         }
     }
 }
 
+// What PhysicsSystem needs from each kind of collider component.
+trait Collider {
+    // The collider's shape with the entity at `position`.
+    fn shape_at(&self, position: Vector2<f32>) -> Shape;
+    // (layer, mask, body)
+    fn flags(&self) -> (u32, u32, BodyKind);
+    fn shape_index_mut(&mut self) -> &mut Option<ShapeIndex>;
+}
+
+// Gameplay can change a collider's layers or body kind at any time (a ghost going solid, a door
+// sliding open), or add and remove its Trigger or Ledge, so keep the shape in step.
+fn sync_shape(space: &mut Space, si: ShapeIndex, layer: u32, mask: u32, body: BodyKind, sensor: bool, one_way: Option<Vector2<f32>>) {
+    let stale = space.shape(si)
+        .map(|x| x.layer() != layer || x.mask() != mask || x.body() != body || x.sensor() != sensor || x.one_way() != one_way)
+        .unwrap_or(false);
+    if stale {
+        let mut shape = space.shape_mut(si).unwrap();
+        *shape.layer_mut() = layer;
+        *shape.mask_mut() = mask;
+        *shape.body_mut() = body;
+        *shape.sensor_mut() = sensor;
+        *shape.one_way_mut() = one_way;
+    }
+}
+
 impl<'a> System<'a> for PhysicsSystem {
     type SystemData = (Entities<'a>,
                        Read<'a, DeltaTime>,
@@ -42,7 +137,17 @@ impl<'a> System<'a> for PhysicsSystem {
                        WriteStorage<'a, Position>,
                        WriteStorage<'a, Velocity>,
                        ReadStorage<'a, Friction>,
-                       WriteStorage<'a, CollisionAabb>);
+                       WriteStorage<'a, CollisionAabb>,
+                       WriteStorage<'a, CollisionCircle>,
+                       WriteStorage<'a, CollisionPolygon>,
+                       WriteStorage<'a, Trigger>,
+                       ReadStorage<'a, Pushable>,
+                       ReadStorage<'a, CollisionResponse>,
+                       ReadStorage<'a, CornerCorrection>,
+                       ReadStorage<'a, Ledge>,
+                       Write<'a, EventChannel<ContactStarted>>,
+                       Write<'a, EventChannel<ContactEnded>>,
+                       Write<'a, EventChannel<LedgeHop>>);
 
     fn run(&mut self, data: Self::SystemData) {
         let (entities,
@@ -51,84 +156,154 @@ impl<'a> System<'a> for PhysicsSystem {
             mut poss,
             mut vels,
             fric,
-            mut aabbs) = data;
+            mut aabbs,
+            mut circles,
+            mut polygons,
+            mut triggers,
+            pushables,
+            responses,
+            corner_corrections,
+            ledges,
+            mut started,
+            mut ended,
+            mut hops) = data;
 
         let mut space = &mut space.0;
 
         // Update shapes and create them if they don't exist.
         for (ent, pos, aabb) in (&entities, &mut poss, &mut aabbs).join() {
-            if aabb.shape_index.is_none() {
-                // Allocate a shape
-                let si = space.add_shape(Shape::new_rectangle_xywh(pos.position.x, pos.position.y, aabb.size.0, aabb.size.1));
-                aabb.shape_index = Some(si);
-                // Insert the shape into our mapping
-                self.shape_index_mapping.insert(si, ent);
-            } else {
-                // Sync our position with the position of our shape.
-                let shape = space.shape_mut(aabb.shape_index.unwrap());
-                if let Some(mut shape) = shape {
-                    pos.position.x = shape.x();
-                    pos.position.y = shape.y();
-                }
-            }
+            self.sync_collider(&mut space, ent, pos, aabb, triggers.get(ent).is_some(), ledges.get(ent).map(|x| x.direction));
+        }
+        for (ent, pos, circle) in (&entities, &mut poss, &mut circles).join() {
+            self.sync_collider(&mut space, ent, pos, circle, triggers.get(ent).is_some(), ledges.get(ent).map(|x| x.direction));
+        }
+        for (ent, pos, polygon) in (&entities, &mut poss, &mut polygons).join() {
+            self.sync_collider(&mut space, ent, pos, polygon, triggers.get(ent).is_some(), ledges.get(ent).map(|x| x.direction));
         }
 
-        let mut test_collisions = |ent: Entity, pos: &mut Position, space: &mut Space| -> Option<Vector2<f32>> {
-            let aabb = aabbs.get(ent);
-            let mut normal = Vector2::new(0.0, 0.0);
+        // This shouldn't fail for colliders, cuz we just allocated the shapes.
+        let shape_index_of = |ent: Entity| {
+            aabbs.get(ent).and_then(|x| x.shape_index)
+                .or_else(|| circles.get(ent).and_then(|x| x.shape_index))
+                .or_else(|| polygons.get(ent).and_then(|x| x.shape_index))
+        };
 
-            if let Some(mut aabb) = aabb {
-                let si = aabb.shape_index.unwrap(); // This shouldn't fail, cuz we just allocated the shape.
-                for coll in space.check_collisions(si) {
-                    let oe = self.shape_index_mapping.get(&coll.shape_b).unwrap();
-                    let mpv = Vector2::new(coll.resolve_x, coll.resolve_y);
-                    pos.position += mpv;
-                    let shape = space.shape_mut(si).unwrap();
-                    *shape.x_mut() = pos.position.x;
-                    *shape.y_mut() = pos.position.y;
-                    normal += mpv.normalize();
-                    // If we wanted too support pushing, how...
-                }
+        // Mass of every dynamic body that can be pushed around.
+        let pushable: HashMap<ShapeIndex, f32> = (&entities, &pushables).join()
+            .filter_map(|(ent, pushable)| shape_index_of(ent).map(|si| (si, pushable.mass)))
+            .filter(|&(si, _)| space.shape(si).map(|x| x.body() == BodyKind::Dynamic).unwrap_or(false))
+            .collect();
+
+        // Pushes the entity out of anything it's stuck in, and gives back the normal of each.
+        let test_collisions = |si: ShapeIndex, pos: &mut Position, space: &mut Space| -> Vec<Vector2<f32>> {
+            let mut normals = vec![];
+            let mass = pushable.get(&si).cloned().unwrap_or(DEFAULT_MASS);
+
+            for coll in space.check_collisions(si).into_iter().filter(|x| !x.sensor && !x.one_way) {
+                let mut mpv = Vector2::new(coll.resolve_x, coll.resolve_y);
+                // Split the push by mass. Whatever the other body can't take (say it's against a wall) is on us.
+                mpv += space.push(coll.shape_b, -mpv, mass, &pushable, MAX_PUSH_DEPTH);
+                pos.position += mpv;
+                let mut shape = space.shape_mut(si).unwrap();
+                *shape.x_mut() = pos.position.x;
+                *shape.y_mut() = pos.position.y;
+                normals.push(Vector2::new(coll.resolve_x, coll.resolve_y).normalize());
             }
 
-            if normal.magnitude() > 0.0 {
-                Some(normal)
-            } else {
-                None
-            }
+            normals
         };
 
-        let move_ent = |ent: Entity, pos: &mut Position, by: Vector2<f32>, aabb: &CollisionAabb, space: &mut Space| {
-            let sweep_steps = 5;
-            let mut last_worked = Vector2::new(0.0, 0.0);
-            for i in 1..=sweep_steps {
-                let percent = i as f32 / sweep_steps as f32;
-                let percent_by = by * percent;
-                pos.position -= last_worked;
-                pos.position += percent_by;
-                if let Some(mpv) = test_collisions(ent, pos, space) {
-                    return Some(mpv)
+        // Sweep the shape along the movement, stop at the first thing it would hit, then carry on with
+        // the rest of the move however `response` says (along the surface, or off it). Exact no matter how
+        // fast we go or how thin the wall. Gives back the normal of everything we hit.
+        // With a `corner_threshold`, catching the edge of a wall by that much or less slips us around it instead.
+        // Any one-way shapes we start going over end up in `crossed`.
+        let move_ent = |si: ShapeIndex, pos: &mut Position, by: Vector2<f32>, response: ResponseType, corner_threshold: Option<f32>,
+                        space: &mut Space, crossed: &mut Vec<(Entity, Vector2<f32>)>| {
+            // Anything we're already stuck in gets resolved the old way first.
+            let mut normals = test_collisions(si, pos, space);
+            let mut remaining = by;
+            let mass = pushable.get(&si).cloned().unwrap_or(DEFAULT_MASS);
+
+            for _ in 0..MAX_SLIDES {
+                if remaining.magnitude2() == 0.0 {
+                    break
+                }
+
+                let hit = space.sweep(si, remaining);
+                let travel = if let Some(ref hit) = hit {
+                    // Back off the surface a little so the next sweep starts outside it, not on it.
+                    remaining * hit.time + hit.normal * CONTACT_SKIN
+                } else {
+                    remaining
+                };
+                for (ledge, _) in space.one_way_crossings(si, travel) {
+                    if let (Some(&le), Some(direction)) = (self.shape_index_mapping.get(&ledge), space.shape(ledge).and_then(|x| x.one_way())) {
+                        crossed.push((le, direction));
+                    }
+                }
+                pos.position += travel;
+                {
+                    let mut shape = space.shape_mut(si).unwrap();
+                    *shape.x_mut() = pos.position.x;
+                    *shape.y_mut() = pos.position.y;
+                }
+
+                if let Some(hit) = hit {
+                    remaining *= 1.0 - hit.time;
+                    let into = hit.normal * dot(remaining, hit.normal);
+
+                    // Shove it out of the way and follow it, as far as it went. If it didn't budge it's just a wall.
+                    // Something already resting against a wall still shifts by a hair as it backs off by
+                    // CONTACT_SKIN, which doesn't count.
+                    let pushed = space.push(hit.shape, into, mass, &pushable, MAX_PUSH_DEPTH);
+                    if dot(pushed, -hit.normal) > CONTACT_SKIN {
+                        remaining += pushed - into;
+                    } else if let Some(nudge) = corner_threshold.and_then(|threshold| space.corner_nudge(si, remaining, &hit, threshold)) {
+                        pos.position += nudge;
+                        space.shape_mut(si).unwrap().shift(nudge.x, nudge.y);
+                    } else {
+                        remaining = response.apply(remaining, &[hit.normal]);
+                        normals.push(hit.normal);
+                    }
                 } else {
-                    last_worked = percent_by;
+                    break
                 }
             }
-            // Update shape by full movement vector
-            let shape = space.shape_mut(aabb.shape_index.unwrap()).unwrap();
-            *shape.x_mut() = pos.position.x;
-            *shape.y_mut() = pos.position.y;
-            None
+
+            normals
         };
 
         let dt = delta.0.as_secs_f32();
 
+        let mut crossed = vec![];
+
         for (ent, pos, vel) in (&entities, &mut poss, &mut vels).join() {
-            let aabb = aabbs.get(ent);
-            if let Some(aabb) = aabb {
-                if let Some(mpv) = move_ent(ent, pos, vel.velocity * dt, aabb, &mut space) {
-                    // TODO Preserve velocity length.
-                    let normal = mpv.normalize();
-                    let undesired = normal * dot(vel.velocity, normal);
-                    vel.velocity -= undesired;
+            if let Some(si) = shape_index_of(ent) {
+                match space.shape(si).map(|x| x.body()) {
+                    Some(BodyKind::Dynamic) => {
+                        // Something may have pushed us earlier this frame.
+                        let shape = space.shape(si).unwrap();
+                        pos.position = Vector2::new(shape.x(), shape.y());
+                        let response = responses.get(ent).map(|x| x.0).unwrap_or_default();
+                        crossed.clear();
+                        let corner_threshold = corner_corrections.get(ent).map(|x| x.threshold);
+                        let normals = move_ent(si, pos, vel.velocity * dt, response, corner_threshold, &mut space, &mut crossed);
+                        for &(ledge, direction) in crossed.iter() {
+                            hops.single_write(LedgeHop { entity: ent, ledge, direction });
+                        }
+                        vel.velocity = response.apply(vel.velocity, &normals);
+                    },
+                    // Kinematic bodies go wherever they're told. Anything dynamic in the way gets
+                    // pushed out when it next moves.
+                    Some(BodyKind::Kinematic) => {
+                        pos.position += vel.velocity * dt;
+                        let mut shape = space.shape_mut(si).unwrap();
+                        *shape.x_mut() = pos.position.x;
+                        *shape.y_mut() = pos.position.y;
+                    },
+                    // Static bodies stay put, velocity or not.
+                    _ => {},
                 }
             } else {
                 let vely = vel.velocity * dt;
@@ -136,8 +311,50 @@ impl<'a> System<'a> for PhysicsSystem {
             }
         }
 
-        drop(move_ent);
-        drop(test_collisions);
+        // Pushed bodies only had their shapes moved, so catch their positions up.
+        for (ent, pos, _) in (&entities, &mut poss, &pushables).join() {
+            if let Some(shape) = shape_index_of(ent).and_then(|si| space.shape(si)) {
+                pos.position = Vector2::new(shape.x(), shape.y());
+            }
+        }
+
+        // Now that everything has moved, see what each dynamic body ended up resting against. Moves stop
+        // CONTACT_SKIN off whatever they hit, so allow that much again for rounding.
+        let mut contacts = Contacts::new();
+        for ent in (&entities).join() {
+            if let Some(si) = shape_index_of(ent).filter(|&si| space.shape(si).map(|x| x.body() == BodyKind::Dynamic).unwrap_or(false)) {
+                for (other, normal) in space.touching(si, CONTACT_SKIN * 2.0) {
+                    if let Some(&oe) = self.shape_index_mapping.get(&other) {
+                        PhysicsSystem::record_contact(&mut contacts, ent, oe, normal);
+                    }
+                }
+            }
+        }
+
+        // And who ended up inside each trigger.
+        for (ent, trigger) in (&entities, &mut triggers).join() {
+            trigger.overlapping.clear();
+            if let Some(si) = shape_index_of(ent) {
+                for coll in space.check_collisions(si) {
+                    if let Some(&oe) = self.shape_index_mapping.get(&coll.shape_b) {
+                        trigger.overlapping.push(oe);
+                        PhysicsSystem::record_contact(&mut contacts, ent, oe, Vector2::new(coll.resolve_x, coll.resolve_y).normalize());
+                    }
+                }
+            }
+        }
+
+        for (&(a, b), &normal) in contacts.iter() {
+            if !self.contacts.contains_key(&(a, b)) {
+                started.single_write(ContactStarted { a, b, normal });
+            }
+        }
+        for &(a, b) in self.contacts.keys() {
+            if !contacts.contains_key(&(a, b)) {
+                ended.single_write(ContactEnded { a, b });
+            }
+        }
+        self.contacts = contacts;
 
         for(ent, vel) in (&entities, &mut vels).join() {
             let fric: Option<&Friction> = fric.get(ent);
@@ -173,16 +390,49 @@ impl<'a> System<'a> for InputSystem {
 
 struct EventAreaSystem;
 
-// TODO Implement events, which check for collision, then execute a series of actions, based off of
-// an event script
+// TODO Implement events, which check what's inside each Trigger, then execute a series of actions,
+// based off of an event script
 impl<'a> System<'a> for EventAreaSystem {
-    type SystemData = (Entities<'a>, ReadStorage<'a, Position>);
+    type SystemData = (Entities<'a>, ReadStorage<'a, Position>, ReadStorage<'a, Trigger>);
 
     fn run(&mut self, data: Self::SystemData) {
 
     }
 }
 
+// Makes the entity's collider a sensor: it never pushes anything or gets pushed, it just keeps track of
+// which entities are inside it. PhysicsSystem refreshes `overlapping` every frame.
+#[derive(Component, Clone, Debug, Default)]
+struct Trigger {
+    overlapping: Vec<Entity>,
+}
+
+// Lets other dynamic bodies shove this one around, boulder puzzle style. The heavier it is next to whatever's
+// pushing (DEFAULT_MASS if that has no Pushable of its own), the more of the push it resists. Only works on
+// dynamic bodies.
+#[derive(Component, Clone, Debug)]
+struct Pushable {
+    mass: f32,
+}
+
+// How the entity's velocity reacts to running into things. Without one it's ResponseType::Project.
+#[derive(Component, Clone, Debug)]
+struct CollisionResponse(ResponseType);
+
+// Slips the entity around wall corners it only catches by `threshold` or less, so walking through
+// a doorway slightly off-center doesn't stop it dead.
+#[derive(Component, Clone, Debug)]
+struct CornerCorrection {
+    threshold: f32,
+}
+
+// Makes the entity's collider one-way, like a cliff ledge: bodies moving along `direction` pass
+// straight through (PhysicsSystem publishes a LedgeHop when they start to), anything coming back is blocked.
+#[derive(Component, Clone, Debug)]
+struct Ledge {
+    direction: Vector2<f32>,
+}
+
 #[derive(Component, Clone, Debug)]
 struct Friction {
     friction: f32, // Inverse 1.0 = all friction (no preserved velocity), 0.0 = no friction
@@ -208,14 +458,77 @@ struct Display(DisplayType);
 #[derive(Clone, Debug)]
 enum DisplayType {
     Rectangle(u32, u32, Color),
+    Circle(f32, Color), // Drawn centered on the position
+    Polygon(Vec<Point2<f32>>, Color), // Outline only, points relative to the position
 }
 
 #[derive(Component, Clone, Debug)]
 struct CollisionAabb {
     size: (f32, f32),
+    layer: u32,
+    mask: u32,
+    body: BodyKind,
+    shape_index: Option<ShapeIndex>,
+}
+
+// Unlike CollisionAabb, the entity's position is the center of the circle.
+#[derive(Component, Clone, Debug)]
+struct CollisionCircle {
+    radius: f32,
+    layer: u32,
+    mask: u32,
+    body: BodyKind,
     shape_index: Option<ShapeIndex>,
 }
 
+// Build it with CollisionPolygon::new, so bad points get caught there and not in the middle of PhysicsSystem.
+#[derive(Component, Clone, Debug)]
+struct CollisionPolygon {
+    // Already checked to be convex, positioned at the origin.
+    polygon: Shape,
+    layer: u32,
+    mask: u32,
+    body: BodyKind,
+    shape_index: Option<ShapeIndex>,
+}
+
+impl Collider for CollisionAabb {
+    fn shape_at(&self, position: Vector2<f32>) -> Shape {
+        Shape::new_rectangle_xywh(position.x, position.y, self.size.0, self.size.1)
+    }
+    fn flags(&self) -> (u32, u32, BodyKind) { (self.layer, self.mask, self.body) }
+    fn shape_index_mut(&mut self) -> &mut Option<ShapeIndex> { &mut self.shape_index }
+}
+
+impl Collider for CollisionCircle {
+    fn shape_at(&self, position: Vector2<f32>) -> Shape {
+        Shape::new_circle(position.x, position.y, self.radius)
+    }
+    fn flags(&self) -> (u32, u32, BodyKind) { (self.layer, self.mask, self.body) }
+    fn shape_index_mut(&mut self) -> &mut Option<ShapeIndex> { &mut self.shape_index }
+}
+
+impl Collider for CollisionPolygon {
+    fn shape_at(&self, position: Vector2<f32>) -> Shape {
+        self.polygon.shifted(position.x, position.y)
+    }
+    fn flags(&self) -> (u32, u32, BodyKind) { (self.layer, self.mask, self.body) }
+    fn shape_index_mut(&mut self) -> &mut Option<ShapeIndex> { &mut self.shape_index }
+}
+
+impl CollisionPolygon {
+    // Points are relative to the entity's position, and have to form a convex polygon.
+    fn new(points: Vec<Point2<f32>>, layer: u32, mask: u32, body: BodyKind) -> Result<CollisionPolygon, String> {
+        Ok(CollisionPolygon {
+            polygon: Shape::new_convex_polygon(0.0, 0.0, points).map_err(|e| format!("Error building CollisionPolygon: {:?}", e))?,
+            layer,
+            mask,
+            body,
+            shape_index: None,
+        })
+    }
+}
+
 // TODO Maybe support arbitrary keybindings [NOTE: Advanced]
 fn update_gamepad(rl: &RaylibHandle, cgp: &mut VirtualGamepadState) {
     cgp.l_x_axis = if rl.is_key_down(KeyboardKey::KEY_RIGHT) {
@@ -268,6 +581,16 @@ fn main() {
     world.register::<Display>();
     world.register::<Friction>();
     world.register::<CollisionAabb>();
+    world.register::<CollisionCircle>();
+    world.register::<CollisionPolygon>();
+    world.register::<Trigger>();
+    world.register::<Pushable>();
+    world.register::<CollisionResponse>();
+    world.register::<CornerCorrection>();
+    world.register::<Ledge>();
+
+    let layers = CollisionLayers::load("collision_layers.cfg").unwrap();
+    let wall = layers.layer("wall").unwrap();
 
     let player = world.create_entity()
         .with(Position { position: Vector2::new(0.0, 0.0)})
@@ -275,19 +598,58 @@ fn main() {
         .with(ControllerInput)
         .with(Display(DisplayType::Rectangle(32, 32, Color::RAYWHITE)))
         .with(Friction{ friction: 0.05 })
-        .with(CollisionAabb {size:(32.0, 32.0), shape_index: None})
+        .with(CollisionResponse(ResponseType::Slide))
+        .with(CornerCorrection { threshold: 6.0 })
+        .with(CollisionAabb {size:(32.0, 32.0), layer: layers.layer("player").unwrap(), mask: ALL_LAYERS, body: BodyKind::Dynamic, shape_index: None})
         .build();
 
     world.create_entity()
         .with(Position{ position: Vector2::new(32.0, 32.0)})
         .with(Display(DisplayType::Rectangle(128, 32, Color::RED)))
-        .with(CollisionAabb {size:(128.0, 32.0), shape_index: None})
+        .with(CollisionAabb {size:(128.0, 32.0), layer: wall, mask: ALL_LAYERS, body: BodyKind::Static, shape_index: None})
         .build();
 
     world.create_entity()
         .with(Position{ position: Vector2::new(96.0, 64.0)})
         .with(Display(DisplayType::Rectangle(32, 128, Color::RED)))
-        .with(CollisionAabb {size:(32.0, 128.0), shape_index: None})
+        .with(CollisionAabb {size:(32.0, 128.0), layer: wall, mask: ALL_LAYERS, body: BodyKind::Static, shape_index: None})
+        .build();
+
+    world.create_entity()
+        .with(Position{ position: Vector2::new(240.0, 160.0)})
+        .with(Display(DisplayType::Circle(24.0, Color::RED)))
+        .with(CollisionCircle {radius: 24.0, layer: wall, mask: ALL_LAYERS, body: BodyKind::Static, shape_index: None})
+        .build();
+
+    let diagonal_wall = vec![Point2::new(0.0, 0.0), Point2::new(96.0, 96.0), Point2::new(64.0, 96.0)];
+    world.create_entity()
+        .with(Position{ position: Vector2::new(320.0, 256.0)})
+        .with(Display(DisplayType::Polygon(diagonal_wall.clone(), Color::RED)))
+        .with(CollisionPolygon::new(diagonal_wall, wall, ALL_LAYERS, BodyKind::Static).unwrap())
+        .build();
+
+    // A boulder, twice as heavy as the player, so a shove only moves it a third as far.
+    world.create_entity()
+        .with(Position{ position: Vector2::new(192.0, 288.0)})
+        .with(Display(DisplayType::Rectangle(32, 32, Color::GRAY)))
+        .with(CollisionAabb {size:(32.0, 32.0), layer: wall, mask: ALL_LAYERS, body: BodyKind::Dynamic, shape_index: None})
+        .with(Pushable { mass: 2.0 })
+        .build();
+
+    // A ledge you can hop down off of, but not climb back up.
+    world.create_entity()
+        .with(Position{ position: Vector2::new(176.0, 224.0)})
+        .with(Display(DisplayType::Rectangle(128, 8, Color::GRAY)))
+        .with(CollisionAabb {size:(128.0, 8.0), layer: wall, mask: ALL_LAYERS, body: BodyKind::Static, shape_index: None})
+        .with(Ledge { direction: Vector2::new(0.0, 1.0) })
+        .build();
+
+    // A warp tile, say. Walks right through, but the event system knows when you're on it.
+    world.create_entity()
+        .with(Position{ position: Vector2::new(480.0, 64.0)})
+        .with(Display(DisplayType::Rectangle(32, 32, Color::BLUE)))
+        .with(CollisionAabb {size:(32.0, 32.0), layer: layers.layer("default").unwrap(), mask: ALL_LAYERS, body: BodyKind::Static, shape_index: None})
+        .with(Trigger::default())
         .build();
 
     let mut timer = Timer::new();
@@ -297,11 +659,20 @@ fn main() {
     let mut dispatcher = DispatcherBuilder::new()
         .with(InputSystem, "control", &[])
         .with(PhysicsSystem::new(), "physics", &["control"])
+        .with(EventAreaSystem, "events", &["physics"])
         .build();
 
     let mut current_gamepad = VirtualGamepadState::new();
 
     world.insert(PhysicsSpace(Space::new()));
+    world.insert(layers);
+    world.insert(EventChannel::<ContactStarted>::new());
+    world.insert(EventChannel::<ContactEnded>::new());
+    world.insert(EventChannel::<LedgeHop>::new());
+    world.insert(SignalChannel::new());
+    // Replays and tests re-seed this with a known value; a fresh game just uses the clock.
+    let seed = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    world.insert(ScriptRng::new(seed));
 
 //    let texture = rl.load_texture_from_image(&thread, &image_load).unwrap();
 
@@ -328,6 +699,15 @@ fn main() {
                     DisplayType::Rectangle(w, h, c) => {
                         d.draw_rectangle(pos.position.x as i32, pos.position.y as i32, w as i32, h as i32, c);
                     }
+                    DisplayType::Circle(r, c) => {
+                        d.draw_circle(pos.position.x as i32, pos.position.y as i32, r, c);
+                    }
+                    DisplayType::Polygon(ref points, c) => {
+                        for (p, q) in points.iter().zip(points.iter().cycle().skip(1)) {
+                            d.draw_line((pos.position.x + p.x) as i32, (pos.position.y + p.y) as i32,
+                                        (pos.position.x + q.x) as i32, (pos.position.y + q.y) as i32, c);
+                        }
+                    }
                 }
             }
 //            d.draw_texture_ex(&texture, raylib::math::Vector2::new(0.0, 0.0), 0.0, 1.0, Color::WHITE);
@@ -336,3 +716,166 @@ fn main() {
         std::thread::sleep(Duration::from_secs_f32(0.016));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::shrev::ReaderId;
+
+    // An empty world with the physics set up to step 100ms at a time.
+    fn physics_world() -> (World, PhysicsSystem) {
+        let mut world = World::new();
+        let mut physics = PhysicsSystem::new();
+        System::setup(&mut physics, &mut world);
+        world.insert(DeltaTime(Duration::from_millis(100)));
+        (world, physics)
+    }
+
+    // A dynamic 32x32 box at `at`, standing still.
+    fn add_player(world: &mut World, at: Vector2<f32>) -> Entity {
+        world.create_entity()
+            .with(Position { position: at })
+            .with(Velocity { velocity: Vector2::new(0.0, 0.0), max_velocity: Vector2::new(400.0, 400.0) })
+            .with(CollisionAabb { size: (32.0, 32.0), layer: ALL_LAYERS, mask: ALL_LAYERS, body: BodyKind::Dynamic, shape_index: None })
+            .build()
+    }
+
+    // Sets the player moving at `velocity` and runs the physics for a frame.
+    fn step(world: &mut World, physics: &mut PhysicsSystem, player: Entity, velocity: Vector2<f32>) {
+        world.write_storage::<Velocity>().get_mut(player).unwrap().velocity = velocity;
+        physics.run_now(world);
+        world.maintain();
+    }
+
+    // A player to the left of a static wall.
+    fn wall_world() -> (World, PhysicsSystem, Entity, Entity) {
+        let (mut world, physics) = physics_world();
+        let player = add_player(&mut world, Vector2::new(0.0, 0.0));
+        let wall = world.create_entity()
+            .with(Position { position: Vector2::new(64.0, -32.0) })
+            .with(CollisionAabb { size: (32.0, 96.0), layer: ALL_LAYERS, mask: ALL_LAYERS, body: BodyKind::Static, shape_index: None })
+            .build();
+        (world, physics, player, wall)
+    }
+
+    // Steps the world at `velocity` and gives back (started, ended) counts for the frame.
+    fn step_world(world: &mut World, physics: &mut PhysicsSystem, player: Entity, velocity: Vector2<f32>,
+                  started: &mut ReaderId<ContactStarted>, ended: &mut ReaderId<ContactEnded>) -> (Vec<ContactStarted>, usize) {
+        step(world, physics, player, velocity);
+        let started = world.fetch::<EventChannel<ContactStarted>>().read(started).cloned().collect();
+        let ended = world.fetch::<EventChannel<ContactEnded>>().read(ended).count();
+        (started, ended)
+    }
+
+    // A player at `at` next to a 128x8 ledge at (0, 48) that you can only hop down off of.
+    fn ledge_world(at: Vector2<f32>) -> (World, PhysicsSystem, Entity) {
+        let (mut world, physics) = physics_world();
+        let player = add_player(&mut world, at);
+        world.create_entity()
+            .with(Position { position: Vector2::new(0.0, 48.0) })
+            .with(CollisionAabb { size: (128.0, 8.0), layer: ALL_LAYERS, mask: ALL_LAYERS, body: BodyKind::Static, shape_index: None })
+            .with(Ledge { direction: Vector2::new(0.0, 1.0) })
+            .build();
+        (world, physics, player)
+    }
+
+    // Steps a fresh ledge_world one frame, and gives back where the player ended up and how many hops that fired.
+    fn ledge_frame(at: Vector2<f32>, velocity: Vector2<f32>) -> (Vector2<f32>, usize) {
+        let (mut world, mut physics, player) = ledge_world(at);
+        let mut hops = world.fetch_mut::<EventChannel<LedgeHop>>().register_reader();
+        step(&mut world, &mut physics, player, velocity);
+        let position = world.read_storage::<Position>().get(player).unwrap().position;
+        let hops = world.fetch::<EventChannel<LedgeHop>>().read(&mut hops).count();
+        (position, hops)
+    }
+
+    #[test]
+    fn ledge_hops_only_going_down() {
+        // Down goes straight through, with a hop.
+        let (position, hops) = ledge_frame(Vector2::new(16.0, 0.0), Vector2::new(0.0, 400.0));
+        assert!((position.y - 40.0).abs() < 1e-3);
+        assert_eq!(hops, 1);
+
+        // Up stops under it.
+        let (position, hops) = ledge_frame(Vector2::new(16.0, 60.0), Vector2::new(0.0, -400.0));
+        assert!((position.y - 56.0).abs() < 0.05, "{:?}", position);
+        assert_eq!(hops, 0);
+
+        // So does walking into its end from the side, even heading a touch downwards.
+        for &velocity in [Vector2::new(400.0, 0.0), Vector2::new(400.0, 0.001), Vector2::new(400.0, 20.0)].iter() {
+            let (position, hops) = ledge_frame(Vector2::new(-40.0, 40.0), velocity);
+            assert!((position.x + 32.0).abs() < 0.05, "{:?} got to {:?}", velocity, position);
+            assert_eq!(hops, 0);
+        }
+
+        // Turning back halfway through the hop doesn't get you back up.
+        let (mut world, mut physics, player) = ledge_world(Vector2::new(16.0, 0.0));
+        let mut hops = world.fetch_mut::<EventChannel<LedgeHop>>().register_reader();
+        step(&mut world, &mut physics, player, Vector2::new(0.0, 200.0));
+        assert_eq!(world.fetch::<EventChannel<LedgeHop>>().read(&mut hops).count(), 1);
+        let halfway = world.read_storage::<Position>().get(player).unwrap().position;
+        assert!((halfway.y - 20.0).abs() < 1e-3, "{:?}", halfway);
+        for _ in 0..3 {
+            step(&mut world, &mut physics, player, Vector2::new(0.0, -400.0));
+            let position = world.read_storage::<Position>().get(player).unwrap().position;
+            assert!(position.y >= halfway.y - 1e-3, "got back up to {:?}", position);
+        }
+        assert_eq!(world.fetch::<EventChannel<LedgeHop>>().read(&mut hops).count(), 0);
+    }
+
+    #[test]
+    fn pushing_a_boulder_into_a_wall_stops_you() {
+        // The boulder is already resting against the wall, so there's nowhere for it to go.
+        let (mut world, mut physics) = physics_world();
+        let x = 1000.3;
+        let player = add_player(&mut world, Vector2::new(x, 0.0));
+        let boulder = world.create_entity()
+            .with(Position { position: Vector2::new(x + 40.0, 0.0) })
+            .with(CollisionAabb { size: (32.0, 32.0), layer: ALL_LAYERS, mask: ALL_LAYERS, body: BodyKind::Dynamic, shape_index: None })
+            .with(Pushable { mass: 2.0 })
+            .build();
+        world.create_entity()
+            .with(Position { position: Vector2::new(x + 72.0 + CONTACT_SKIN, -32.0) })
+            .with(CollisionAabb { size: (32.0, 96.0), layer: ALL_LAYERS, mask: ALL_LAYERS, body: BodyKind::Static, shape_index: None })
+            .build();
+
+        for _ in 0..3 {
+            step(&mut world, &mut physics, player, Vector2::new(200.0, 0.0));
+            // Running into it counts as hitting a wall, so the velocity gets projected away.
+            assert_eq!(world.read_storage::<Velocity>().get(player).unwrap().velocity, Vector2::new(0.0, 0.0));
+        }
+        let positions = world.read_storage::<Position>();
+        assert!((positions.get(boulder).unwrap().position.x - (x + 40.0)).abs() < 0.05);
+        assert!((positions.get(player).unwrap().position.x - (x + 8.0)).abs() < 0.05);
+    }
+
+    #[test]
+    fn contact_lasts_while_resting() {
+        let (mut world, mut physics, player, wall) = wall_world();
+        let mut started = world.fetch_mut::<EventChannel<ContactStarted>>().register_reader();
+        let mut ended = world.fetch_mut::<EventChannel<ContactEnded>>().register_reader();
+        let mut step = |world: &mut World, v: Vector2<f32>| step_world(world, &mut physics, player, v, &mut started, &mut ended);
+
+        // Still a way off.
+        let (s, e) = step(&mut world, Vector2::new(100.0, 0.0));
+        assert!(s.is_empty() && e == 0);
+
+        // Runs into the wall.
+        let (s, e) = step(&mut world, Vector2::new(400.0, 0.0));
+        assert_eq!(s.len(), 1);
+        assert_eq!((s[0].a, s[0].b), (player, wall));
+        assert!((s[0].normal - Vector2::new(-1.0, 0.0)).magnitude() < 1e-4);
+        assert_eq!(e, 0);
+
+        // Standing still against it, and sliding along it, it's still touching, so nothing new happens.
+        let (s, e) = step(&mut world, Vector2::new(0.0, 0.0));
+        assert!(s.is_empty() && e == 0);
+        let (s, e) = step(&mut world, Vector2::new(0.0, 50.0));
+        assert!(s.is_empty() && e == 0);
+
+        // Walking away ends it.
+        let (s, e) = step(&mut world, Vector2::new(-100.0, 0.0));
+        assert!(s.is_empty());
+        assert_eq!(e, 1);
+    }
+}
@@ -1,5 +1,9 @@
 pub mod tokens;
+pub mod signal;
+pub mod random;
 
 pub mod prelude {
     pub use super::tokens::*;
+    pub use super::signal::*;
+    pub use super::random::*;
 }
\ No newline at end of file
@@ -0,0 +1,33 @@
+use specs::shrev::EventChannel;
+use crate::script::tokens::TokenData;
+
+/// A named signal, raised by `:emit "name" payload` in a script or by any Rust system.
+/// Scripts wait on them with `:await "name"` or handle them with `on signal "name" { }`.
+#[derive(Debug, Clone)]
+pub struct ScriptSignal {
+    name: String,
+    payload: Option<TokenData>,
+}
+
+impl ScriptSignal {
+    pub fn new<S: Into<String>>(name: S) -> ScriptSignal {
+        ScriptSignal {
+            name: name.into(),
+            payload: None,
+        }
+    }
+
+    pub fn with_payload<S: Into<String>>(name: S, payload: TokenData) -> ScriptSignal {
+        ScriptSignal {
+            name: name.into(),
+            payload: Some(payload),
+        }
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+    pub fn payload(&self) -> Option<&TokenData> { self.payload.as_ref() }
+}
+
+/// The channel all script signals go through. Lives in the specs `World` as a resource,
+/// so systems publish with `Write<SignalChannel>` and subscribe by registering a reader.
+pub type SignalChannel = EventChannel<ScriptSignal>;
@@ -3,6 +3,7 @@ pub enum TokenType {
     CommandIdentifier,
     Identifier,
     Number,
+    Integer,
     String,
     Keyword,
 
@@ -17,6 +18,7 @@ pub enum TokenType {
     Asterisk,
     AsteriskAsterisk,
     ForwardSlash,
+    Percent,
     // TODO Add support for +=, -=, etc.
     Not, // Supports both ! and ~
     NotEqual, // Supports both != and ~=
@@ -36,7 +38,7 @@ pub enum TokenType {
 pub enum Keyword {
     If, Then,
     Elseif, Else,
-
+    On, Signal,
 }
 
 #[derive(Debug, Clone)]
@@ -45,6 +47,7 @@ pub enum TokenData {
     Identifier(String),
     CommandIdentifier(String),
     Number(f64),
+    Integer(i64), // Literals without a decimal point, so counts and IDs stay exact
     String(String),
 }
 